@@ -7,7 +7,10 @@ pub use sys::AVCodecID;
 
 use std::ffi::{CString, CStr};
 use std::io::{self, Read, Write};
+use std::collections::HashMap;
 use std::{slice, ptr, mem, time};
+use std::sync::{Arc, Mutex, Once};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use libc::{c_char, c_int, c_void, uint8_t};
 
 error_chain! {
@@ -20,6 +23,10 @@ error_chain! {
             description("Failed to allocate a necessary structure")
                 display("Allocation failed(OOM)")
         }
+        Unsupported(what: String) {
+            description("Unsupported container or codec")
+                display("{}", what)
+        }
     }
 }
 
@@ -37,7 +44,17 @@ macro_rules! ck_null {
     }
 }
 
+// ffmpeg's log functions take a va_list, which is passed as a pointer on all supported platforms
+extern "C" {
+    fn av_log_set_callback(cb: Option<unsafe extern "C" fn(*mut c_void, c_int, *const c_char, *mut c_void)>);
+    fn av_log_default_callback(avcl: *mut c_void, level: c_int, fmt: *const c_char, vl: *mut c_void);
+    fn av_log_format_line(avcl: *mut c_void, level: c_int, fmt: *const c_char, vl: *mut c_void,
+                          line: *mut c_char, line_size: c_int, print_prefix: *mut c_int);
+}
+
 const FFMPEG_BUFFER_SIZE: usize = 4096;
+// Corrupt packets skipped before an input is considered broken
+const MAX_DECODE_ERRORS: usize = 100;
 
 pub struct Graph {
     #[allow(dead_code)] // The graph needs to be kept as context for the filters
@@ -48,11 +65,26 @@ pub struct Graph {
     out_frame: *mut sys::AVFrame,
     input: GraphInput,
     outputs: Vec<GraphOutput>,
+    stats: Arc<GraphStats>,
+}
+
+/// Counters which are updated while a graph runs
+#[derive(Debug, Default)]
+pub struct GraphStats {
+    /// Samples decoded from the input
+    pub samples: AtomicUsize,
+    /// Packets which failed to decode and were skipped
+    pub decode_errors: AtomicUsize,
+    /// Change of integrated loudness by a loudnorm filter in dB, which it reports once the graph
+    /// is freed. Only known if the filter was configured with print_format=json.
+    pub gain: Mutex<Option<f64>>,
 }
 
 pub struct GraphBuilder {
     graph: GraphP,
     input: GraphInput,
+    /// Filters applied to the input before it's split up for the outputs, in order
+    filters: Vec<*mut sys::AVFilterContext>,
     outputs: Vec<GraphOutput>,
 }
 
@@ -81,6 +113,8 @@ pub struct Output {
     header_signal: fn(*mut c_void),
     packet_signal: fn(*mut c_void, f64),
     body_signal: fn(*mut c_void),
+    /// Whether inputs with more than two channels keep them instead of being downmixed to stereo
+    surround: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -93,6 +127,36 @@ pub struct Metadata {
     pub track: Option<String>,
 }
 
+/// Description of a built graph, for diagnostics
+#[derive(Debug, Clone)]
+pub struct GraphInfo {
+    pub input: StreamInfo,
+    pub filters: Vec<FilterInfo>,
+    pub outputs: Vec<StreamInfo>,
+}
+
+#[derive(Debug, Clone)]
+pub struct StreamInfo {
+    pub format: String,
+    pub codec: String,
+    pub sample_rate: i32,
+    pub sample_fmt: String,
+    pub channels: i32,
+    pub channel_layout: u64,
+    pub bit_rate: i64,
+    pub frame_size: i32,
+}
+
+#[derive(Debug, Clone)]
+pub struct FilterInfo {
+    /// Name of the filter instance, e.g. "out0"
+    pub name: String,
+    /// Name of the filter, e.g. "abuffersink"
+    pub filter: String,
+    /// Names of the filter instances feeding into this one
+    pub inputs: Vec<String>,
+}
+
 struct Opaque {
     ptr: *mut c_void,
     cleanup: fn(*mut c_void),
@@ -100,6 +164,8 @@ struct Opaque {
 
 struct GraphP {
     ptr: *mut sys::AVFilterGraph,
+    /// Addresses of the loudnorm filters whose stats are captured from the log
+    loudnorm: Vec<usize>,
 }
 
 pub trait Sink : Write {
@@ -145,8 +211,42 @@ impl Graph {
         }
     }
 
+    /// Returns the counters of the graph, which stay available after it was run
+    pub fn stats(&self) -> Arc<GraphStats> {
+        self.stats.clone()
+    }
+
+    /// Describes the graph: the decoded input, all filters and how they're linked, and the
+    /// encoder settings of each output
+    pub fn info(&self) -> GraphInfo {
+        unsafe {
+            let g = self.graph.ptr;
+            let filters = (0..(*g).nb_filters as isize)
+                .map(|i| {
+                    let f = *(*g).filters.offset(i);
+                    let inputs = (0..(*f).nb_inputs as isize)
+                        .map(|j| c_str((*(*(*(*f).inputs.offset(j))).src).name))
+                        .collect();
+                    FilterInfo {
+                        name: c_str((*f).name),
+                        filter: c_str((*(*f).filter).name),
+                        inputs,
+                    }
+                })
+                .collect();
+            GraphInfo {
+                input: stream_info(c_str((*(*self.input.input.ctx).iformat).name), self.input.input.codec_ctx),
+                filters,
+                outputs: self.outputs.iter()
+                    .map(|o| stream_info(c_str((*(*o.output.ctx).oformat).name), o.output.codec_ctx))
+                    .collect(),
+            }
+        }
+    }
+
     unsafe fn execute_tc(&mut self) -> Result<()> {
-        self.input.input.read_frames(self.in_frame, || {
+        self.input.input.read_frames(self.in_frame, &self.stats.decode_errors, || {
+            self.stats.samples.fetch_add((*self.in_frame).nb_samples as usize, Ordering::Relaxed);
             (*self.in_frame).pts = sys::av_frame_get_best_effort_timestamp(self.in_frame);
             let pres = self.process_frame(self.in_frame);
             sys::av_frame_unref(self.in_frame);
@@ -201,6 +301,27 @@ impl Graph {
     }
 }
 
+unsafe fn stream_info(format: String, codec_ctx: *mut sys::AVCodecContext) -> StreamInfo {
+    StreamInfo {
+        format,
+        codec: c_str(sys::avcodec_get_name((*codec_ctx).codec_id)),
+        sample_rate: (*codec_ctx).sample_rate,
+        sample_fmt: c_str(sys::av_get_sample_fmt_name((*codec_ctx).sample_fmt)),
+        channels: (*codec_ctx).channels,
+        channel_layout: (*codec_ctx).channel_layout,
+        bit_rate: (*codec_ctx).bit_rate,
+        frame_size: (*codec_ctx).frame_size,
+    }
+}
+
+unsafe fn c_str(s: *const c_char) -> String {
+    if s.is_null() {
+        String::new()
+    } else {
+        CStr::from_ptr(s).to_string_lossy().into_owned()
+    }
+}
+
 impl Drop for Graph {
     fn drop(&mut self) {
         unsafe {
@@ -238,12 +359,33 @@ impl GraphBuilder {
                     input,
                     ctx: buffersrc_ctx,
                 },
+                filters: Vec::new(),
                 outputs: Vec::new(),
-                graph: GraphP { ptr: graph },
+                graph: GraphP { ptr: graph, loudnorm: Vec::new() },
             })
         }
     }
 
+    /// Appends an ffmpeg filter, e.g. ("loudnorm", "I=-16"), to the chain every output is fed
+    /// from
+    pub fn add_filter(&mut self, name: &str, args: &str) -> Result<&mut Self> {
+        let id = format!("filter{}", self.filters.len());
+        unsafe {
+            let filter = sys::avfilter_get_by_name(str_conv!(name));
+            if filter.is_null() {
+                return Err(ErrorKind::Unsupported(format!("ffmpeg has no filter named {}", name)).into());
+            }
+            let ctx = sys::avfilter_graph_alloc_filter(self.graph.ptr, filter, str_conv!(&id[..]));
+            ck_null!(ctx);
+            match sys::avfilter_init_str(ctx, str_conv!(args)) {
+                0 => { }
+                e => return Err(ErrorKind::FFmpeg("failed to initialize filter", e).into()),
+            }
+            self.filters.push(ctx);
+        }
+        Ok(self)
+    }
+
     pub fn add_output(&mut self, output: Output) -> Result<&mut Self> {
         let id = format!("out{}", self.outputs.len());
         unsafe {
@@ -261,8 +403,13 @@ impl GraphBuilder {
             if (*output.codec_ctx).bit_rate == 0 {
                 (*output.codec_ctx).bit_rate = (*input.codec_ctx).bit_rate;
             }
-            (*output.codec_ctx).channel_layout = (*input.codec_ctx).channel_layout;
-            (*output.codec_ctx).channels = sys::av_get_channel_layout_nb_channels((*input.codec_ctx).channel_layout);
+            let mut layout = (*input.codec_ctx).channel_layout;
+            if !output.surround && sys::av_get_channel_layout_nb_channels(layout) > 2 {
+                // The filtergraph downmixes to whatever the buffersink asks for
+                layout = sys::av_get_default_channel_layout(2) as u64;
+            }
+            (*output.codec_ctx).channel_layout = layout;
+            (*output.codec_ctx).channels = sys::av_get_channel_layout_nb_channels(layout);
             let time_base = sys::AVRational {
                 num: 1,
                 den: (*output.codec_ctx).sample_rate,
@@ -314,7 +461,7 @@ impl GraphBuilder {
         Ok(self)
     }
 
-    pub fn build(self) -> Result<Graph> {
+    pub fn build(mut self) -> Result<Graph> {
         unsafe {
             // Create the audio split filter and wire it up
             let asplit = sys::avfilter_get_by_name(str_conv!("asplit"));
@@ -329,7 +476,15 @@ impl GraphBuilder {
                 0 => { }
                 e => return Err(ErrorKind::FFmpeg("failed to initialize asplit", e).into()),
             }
-            match sys::avfilter_link(self.input.ctx, 0, asplit_ctx, 0) {
+            let mut prev = self.input.ctx;
+            for &f in self.filters.iter() {
+                match sys::avfilter_link(prev, 0, f, 0) {
+                    0 => { }
+                    e => return Err(ErrorKind::FFmpeg("failed to link filter", e).into()),
+                }
+                prev = f;
+            }
+            match sys::avfilter_link(prev, 0, asplit_ctx, 0) {
                 0 => { }
                 e => return Err(ErrorKind::FFmpeg("failed to link input to asplit", e).into()),
             }
@@ -352,6 +507,14 @@ impl GraphBuilder {
                 sys::av_buffersink_set_frame_size(o.ctx, (*o.output.codec_ctx).frame_size as u32);
             }
 
+            let stats = Arc::new(GraphStats::default());
+            for &f in self.filters.iter() {
+                if c_str((*(*f).filter).name) == "loudnorm" {
+                    loudnorm_filters().lock().unwrap().insert(f as usize, stats.clone());
+                    self.graph.loudnorm.push(f as usize);
+                }
+            }
+
             Ok(Graph {
                 graph: self.graph,
                 input: self.input,
@@ -359,6 +522,7 @@ impl GraphBuilder {
                 out_frame: sys::av_frame_alloc(),
                 outputs: self.outputs,
                 splitter: asplit_ctx,
+                stats,
             })
         }
     }
@@ -383,7 +547,7 @@ impl Input {
             (*ps).pb = io_ctx;
             let format = sys::av_find_input_format(str_conv!(container));
             if format.is_null() {
-                bail!("Could not derive format from container!");
+                bail!(ErrorKind::Unsupported(format!("could not derive format from container {}", container)));
             }
             let ctx = match sys::avformat_open_input(&mut ps, ptr::null(), format, ptr::null_mut()) {
                 0 => ps,
@@ -399,7 +563,7 @@ impl Input {
                 e => return Err(ErrorKind::FFmpeg("failed to get audio stream from input", e).into()),
             };
             if codec.is_null() {
-                bail!("Failed to find a suitable codec!");
+                bail!(ErrorKind::Unsupported("failed to find a suitable decoder".to_owned()));
             }
 
             let codec_ctx = sys::avcodec_alloc_context3(codec);
@@ -448,6 +612,16 @@ impl Input {
         }
     }
 
+    /// Overrides a metadata tag of the input, which is carried over into all outputs of a graph
+    pub fn set_metadata(&mut self, key: &str, value: &str) -> Result<()> {
+        unsafe {
+            match sys::av_dict_set(&mut (*self.ctx).metadata, str_conv!(key), str_conv!(value), 0) {
+                e if e >= 0 => Ok(()),
+                e => Err(ErrorKind::FFmpeg("failed to set metadata", e).into()),
+            }
+        }
+    }
+
     unsafe fn get_metadata_val(&self, opt: &str) -> Option<String> {
         let entry = sys::av_dict_get((*self.ctx).metadata, str_conv!(opt), ptr::null(), 0);
         if entry.is_null() {
@@ -462,7 +636,7 @@ impl Input {
         }
     }
 
-    unsafe fn read_frames<F: FnMut() -> Result<()>>(&self, frame: *mut sys::AVFrame, mut f: F) -> Result<()> {
+    unsafe fn read_frames<F: FnMut() -> Result<()>>(&self, frame: *mut sys::AVFrame, errors: &AtomicUsize, mut f: F) -> Result<()> {
         let mut packet: sys::AVPacket = mem::uninitialized();
         packet.data = ptr::null_mut();
         packet.size = 0;
@@ -486,7 +660,11 @@ impl Input {
             match { let r = sys::avcodec_send_packet(self.codec_ctx, &packet); sys::av_packet_unref(&mut packet); r} {
                 0 => { }
                 e if e == sys::AVERROR_EOF => { break 'outer; }
-                e  => { return Err(ErrorKind::FFmpeg("failed to decode packet", e).into()); }
+                // Corrupt packets are skipped, unless there are too many of them
+                e if errors.fetch_add(1, Ordering::Relaxed) + 1 >= MAX_DECODE_ERRORS => {
+                    return Err(ErrorKind::FFmpeg("failed to decode packet", e).into());
+                }
+                _ => { continue 'outer; }
             }
 
             loop {
@@ -570,7 +748,7 @@ impl Output {
 
             let codec = sys::avcodec_find_encoder(codec_id);
             if codec.is_null() {
-                bail!("invalid codec provided!");
+                bail!(ErrorKind::Unsupported(format!("no encoder available for {:?}", codec_id)));
             }
             let codec_ctx = sys::avcodec_alloc_context3(codec);
             ck_null!(codec_ctx);
@@ -595,10 +773,17 @@ impl Output {
                 header_signal: sink_header_written::<T>,
                 packet_signal: sink_packet_written::<T>,
                 body_signal: sink_body_written::<T>,
+                surround: false,
             })
         }
     }
 
+    /// Keeps the channels of surround inputs rather than downmixing them to stereo. The codec must
+    /// support the input's channel layout.
+    pub fn set_surround(&mut self, surround: bool) {
+        self.surround = surround;
+    }
+
     unsafe fn write_frame(&self, frame: *mut sys::AVFrame) -> Result<()> {
         let mut out_pkt: sys::AVPacket = mem::uninitialized();
         out_pkt.data = ptr::null_mut();
@@ -713,8 +898,59 @@ fn sink_body_written<T: Sink + Sized>(opaque: *mut c_void) {
 impl Drop for GraphP {
     fn drop(&mut self) {
         unsafe {
+            // loudnorm logs its stats while being freed
             sys::avfilter_graph_free(&mut self.ptr);
         }
+        if !self.loudnorm.is_empty() {
+            let mut filters = loudnorm_filters().lock().unwrap();
+            for f in self.loudnorm.iter() {
+                filters.remove(f);
+            }
+        }
+    }
+}
+
+/// Graph stats by the address of the loudnorm filters reporting to them
+fn loudnorm_filters() -> &'static Mutex<HashMap<usize, Arc<GraphStats>>> {
+    static INIT: Once = Once::new();
+    static mut FILTERS: *const Mutex<HashMap<usize, Arc<GraphStats>>> = 0 as *const _;
+    unsafe {
+        INIT.call_once(|| FILTERS = Box::into_raw(Box::new(Mutex::new(HashMap::new()))));
+        &*FILTERS
+    }
+}
+
+/// Picks the stats of loudnorm filters out of the log, passing everything else on to ffmpeg's
+/// default logger
+unsafe extern "C" fn log_callback(avcl: *mut c_void, level: c_int, fmt: *const c_char, vl: *mut c_void) {
+    let stats = match loudnorm_filters().lock() {
+        Ok(f) => f.get(&(avcl as usize)).cloned(),
+        Err(_) => None,
+    };
+    let stats = match stats {
+        Some(s) => s,
+        None => return av_log_default_callback(avcl, level, fmt, vl),
+    };
+    let mut line = [0 as c_char; 2048];
+    let mut prefix = 0;
+    av_log_format_line(avcl, level, fmt, vl, line.as_mut_ptr(), line.len() as c_int, &mut prefix);
+    let line = CStr::from_ptr(line.as_ptr()).to_string_lossy();
+    if let (Some(i), Some(o)) = (json_number(&line, "input_i"), json_number(&line, "output_i")) {
+        if let Ok(mut gain) = stats.gain.lock() {
+            *gain = Some(o - i);
+        }
+    }
+}
+
+/// Finds a number of the form "key" : "-12.3" in the JSON loudnorm prints. Silence measures as
+/// -inf, which is left out.
+fn json_number(s: &str, key: &str) -> Option<f64> {
+    let start = s.find(&format!("\"{}\"", key))? + key.len() + 2;
+    let value = s[start..].trim_start_matches(|c: char| c == ' ' || c == ':' || c == '"');
+    let end = value.find('"')?;
+    match value[..end].parse::<f64>() {
+        Ok(v) if v.is_finite() => Some(v),
+        _ => None,
     }
 }
 
@@ -763,12 +999,13 @@ pub fn init() {
     unsafe {
         sys::av_register_all();
         sys::avfilter_register_all();
+        av_log_set_callback(Some(log_callback));
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{GraphBuilder, Input, Output, init, Result};
+    use super::{GraphBuilder, Input, Output, init, json_number, Result};
     use std::fs::File;
 
     #[test]
@@ -811,6 +1048,16 @@ mod tests {
         gb.build()?.run()
     }
 
+    #[test]
+    fn test_json_number() {
+        let stats = "[Parsed_loudnorm_0 @ 0x1] \n{\n\t\"input_i\" : \"-23.51\",\n\t\"input_tp\" : \"-inf\",\n\t\
+                     \"output_i\" : \"-16.02\"\n}\n";
+        assert_eq!(json_number(stats, "input_i"), Some(-23.51));
+        assert_eq!(json_number(stats, "output_i"), Some(-16.02));
+        assert_eq!(json_number(stats, "input_tp"), None);
+        assert_eq!(json_number(stats, "target_offset"), None);
+    }
+
     #[test]
     fn test_metadata() {
         init();
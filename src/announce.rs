@@ -0,0 +1,59 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::Read;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use kaeru::Metadata;
+
+use config::AnnounceConfig;
+use error::{Error, Result};
+use queue::QueueEntry;
+use util;
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Builds the announcement text for the transition between two tracks
+pub fn text(cfg: &AnnounceConfig, prev: (&QueueEntry, Option<&Metadata>), next: (&QueueEntry, Option<&Metadata>)) -> String {
+    cfg.text
+        .replace("{prev}", &describe(prev.0, prev.1))
+        .replace("{next}", &describe(next.0, next.1))
+}
+
+/// Runs the configured TTS command, returning the audio it produced. The command gets the text to
+/// speak in $KAWA_TEXT (%KAWA_TEXT% on Windows) and must write the audio to $KAWA_OUT.
+pub fn synthesize(cfg: &AnnounceConfig, text: &str) -> Result<Vec<u8>> {
+    let out = env::temp_dir().join(format!("kawa-announce-{}-{}.{}",
+                                           ::std::process::id(),
+                                           COUNTER.fetch_add(1, Ordering::Relaxed),
+                                           cfg.format));
+    debug!("Synthesizing announcement {:?}", text);
+    let status = util::shell(&cfg.command)
+        .env("KAWA_TEXT", text)
+        .env("KAWA_OUT", &out)
+        .status()
+        .map_err(|e| Error::Announce(format!("failed to run tts command: {}", e)))?;
+    if !status.success() {
+        fs::remove_file(&out).ok();
+        return Err(Error::Announce(format!("tts command exited with {}", status)));
+    }
+    let mut data = Vec::new();
+    let res = File::open(&out).and_then(|mut f| f.read_to_end(&mut data));
+    fs::remove_file(&out).ok();
+    res.map_err(|e| Error::FileMissing(out.to_string_lossy().into_owned(), e))?;
+    Ok(data)
+}
+
+fn describe(entry: &QueueEntry, md: Option<&Metadata>) -> String {
+    let field = |name: &str| match entry.data.get(name) {
+        Some(&::serde_json::Value::String(ref s)) => Some(s.clone()),
+        _ => None,
+    };
+    let title = md.and_then(|m| m.title.clone()).or_else(|| field("title"));
+    let artist = md.and_then(|m| m.artist.clone()).or_else(|| field("artist"));
+    match (title, artist) {
+        (Some(t), Some(a)) => format!("{} by {}", t, a),
+        (Some(t), None) => t,
+        _ => entry.path.rsplit(::std::path::is_separator).next().unwrap_or(&entry.path)
+            .rsplitn(2, '.').last().unwrap_or(&entry.path).to_owned(),
+    }
+}
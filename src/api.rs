@@ -1,22 +1,71 @@
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
 use std::sync::mpsc::Sender;
 use std::collections::HashMap;
-use std::thread;
-use std::path::Path;
+use std::{fs, thread, time};
 use serde_json as serde;
+use serde_json::Value as JSON;
 use rouille;
+use kaeru;
 
-use queue::{Queue, NewQueueEntry};
-use config::ApiConfig;
+use queue::{Encoding, Queue, QueueBuffer, NewQueueEntry};
+use config::{self, Config};
+use error::{Error, Category, Result};
+use transcode::TranscodeInfo;
+use tc_queue;
+use history;
+use events::Events;
+use util;
+use errlog::{self, ErrLog};
+use faults::{self, Faults};
+use channel::Channels;
+use library::TagFilter;
 
 pub type Listeners = Arc<Mutex<HashMap<usize, Listener>>>;
 type SQueue = Arc<Mutex<Queue>>;
 type ApiChan = Arc<Mutex<Sender<ApiMessage>>>;
 
 struct Server {
-    queue: SQueue,
+    cfg: Config,
+    /// Queues by name, None being the default one. Channels started through the API are added.
+    queues: RwLock<HashMap<Option<String>, Target>>,
     listeners: Listeners,
+    events: Events,
+    errors: ErrLog,
+    idempotency_keys: IdempotencyKeys,
+    channels: Channels,
+}
+
+/// Idempotency keys of recent enqueue requests. Keys are only valid for the queue and endpoint they
+/// were used with.
+struct IdempotencyKeys {
+    window: time::Duration,
+    seen: Mutex<HashMap<IdempotencyKey, Seen>>,
+    /// Notified whenever a request with a key finishes
+    done: Condvar,
+}
+
+type IdempotencyKey = (Option<String>, &'static str, String);
+
+enum Seen {
+    Running,
+    Succeeded(time::Instant),
+}
+
+/// Marks a request with a key as running, recording its outcome when dropped. Also clears the mark
+/// if the request panics, so that retries aren't held up forever.
+struct Running<'a> {
+    keys: &'a IdempotencyKeys,
+    key: IdempotencyKey,
+    succeeded: bool,
+}
+
+/// A queue along with the channel to the radio loop playing it
+#[derive(Clone)]
+struct Target {
+    name: Option<String>,
+    queue: SQueue,
     chan: ApiChan,
+    history: Option<String>,
 }
 
 #[derive(Debug)]
@@ -31,6 +80,7 @@ pub enum ApiMessage {
     Remove(QueuePos),
     Insert(QueuePos, NewQueueEntry),
     Clear,
+    Cue(String),
 }
 
 #[derive(Serialize)]
@@ -38,6 +88,14 @@ pub struct Resp {
     pub success: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorInfo>,
+}
+
+#[derive(Serialize)]
+pub struct ErrorInfo {
+    pub code: &'static str,
+    pub category: Category,
 }
 
 #[derive(Serialize)]
@@ -45,6 +103,11 @@ pub struct Listener {
     pub mount: String,
     pub path: String,
     pub headers: Vec<Header>,
+    pub user_agent: Option<String>,
+    /// Unix time the listener connected at
+    pub connected: u64,
+    /// Bytes of the stream sent to the listener, updated every few seconds
+    pub bytes_sent: u64,
 }
 
 #[derive(Serialize)]
@@ -53,15 +116,92 @@ pub struct Header {
     pub value: String,
 }
 
+#[derive(Serialize)]
+struct Mount {
+    id: usize,
+    mount: String,
+    container: &'static str,
+    codec: &'static str,
+    bitrate: Option<i64>,
+    surround: bool,
+    queue: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct MountUpdate {
+    mount: String,
+    bitrate: Option<i64>,
+    codec: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct NewChannel {
+    name: String,
+    filter: Vec<TagFilter>,
+}
+
+#[derive(Serialize)]
+struct DebugStats {
+    np: TrackStats,
+    next: TrackStats,
+    errors: Vec<errlog::Summary>,
+}
+
+#[derive(Serialize)]
+struct TrackStats {
+    id: u64,
+    path: String,
+    input_offset: usize,
+    input_length: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct DebugGraph {
+    transcode: u64,
+    entry: u64,
+    path: String,
+    input: DebugStream,
+    filters: Vec<DebugFilter>,
+    outputs: Vec<DebugStream>,
+}
+
+#[derive(Serialize)]
+struct DebugStream {
+    format: String,
+    codec: String,
+    sample_rate: i32,
+    sample_fmt: String,
+    channels: i32,
+    channel_layout: String,
+    bit_rate: i64,
+    frame_size: i32,
+}
+
+#[derive(Serialize)]
+struct DebugFilter {
+    name: String,
+    filter: String,
+    inputs: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct DebugThreads {
+    transcoders: Vec<TranscodeInfo>,
+    live_writers: usize,
+    live_readers: usize,
+}
+
 impl Server {
     fn handle_request(&self, req: &rouille::Request) -> rouille::Response {
         router!(req,
                 (GET) (/np) => {
                     debug!("Handling now playing req");
-                    let q = self.queue.lock().unwrap();
-                    rouille::Response::from_data(
-                        "application/json",
-                        serde::to_string(&q.np().entry().serialize()).unwrap())
+                    self.with_target(req, |t| {
+                        let q = t.queue.lock().unwrap();
+                        rouille::Response::from_data(
+                            "application/json",
+                            serde::to_string(&q.np().entry().serialize()).unwrap())
+                    })
                 },
 
                 (GET) (/listeners) => {
@@ -74,110 +214,443 @@ impl Server {
 
                 (GET) (/queue) => {
                     debug!("Handling queue disp req");
-                    let q = self.queue.lock().unwrap();
-                    rouille::Response::from_data(
-                        "application/json",
-                        serde::to_string(&q.entries().iter().map(|e| e.serialize()).collect::<Vec<_>>()).unwrap())
+                    self.with_target(req, |t| {
+                        let q = t.queue.lock().unwrap();
+                        rouille::Response::from_data(
+                            "application/json",
+                            serde::to_string(&q.listing()).unwrap())
+                    })
                 },
 
                 (POST) (/queue/head) => {
-                    match serde::from_reader(req.data().unwrap()).map(|d| NewQueueEntry::deserialize(d)) {
-                        Ok(Some(qe)) => {
-                            debug!("Handling queue head insert");
-                            if Path::new(&qe.path).exists() {
-                                self.chan.lock().unwrap().send(ApiMessage::Insert(QueuePos::Head, qe)).unwrap();
-                                rouille::Response::from_data(
-                                    "application/json",
-                                    serde::to_string(&Resp::success()).unwrap())
-                            } else {
-                                rouille::Response::from_data(
-                                    "application/json",
-                                    serde::to_string(&Resp::failure("file does not exist")).unwrap()
-                                ).with_status_code(400)
-                            }
-                        }
-                        Ok(None) => {
-                            rouille::Response::from_data(
-                                "application/json",
-                                serde::to_string(&Resp::failure("blob must contain path!")).unwrap()
-                            ).with_status_code(400)
-                        }
-                        Err(_) => {
-                            rouille::Response::from_data(
-                                "application/json",
-                                serde::to_string(&Resp::failure("malformed json sent")).unwrap()
-                            ).with_status_code(400)
-                        }
-                    }
+                    debug!("Handling queue head insert");
+                    self.with_target(req, |t| self.insert(req, t, QueuePos::Head))
                 },
 
                 (DELETE) (/queue/head) => {
                     debug!("Handling queue head remove");
-                    self.chan.lock().unwrap().send(ApiMessage::Remove(QueuePos::Head)).unwrap();
-                    rouille::Response::from_data(
-                        "application/json",
-                        serde::to_string(&Resp::success()).unwrap())
+                    self.with_target(req, |t| send(t, ApiMessage::Remove(QueuePos::Head)))
                 },
 
                 (POST) (/queue/tail) => {
                     debug!("Handling queue tail insert");
-                    match serde::from_reader(req.data().unwrap()).map(|d| NewQueueEntry::deserialize(d)) {
-                        Ok(Some(qe)) => {
-                            debug!("Handling queue head insert");
-                            if Path::new(&qe.path).exists() {
-                                self.chan.lock().unwrap().send(ApiMessage::Insert(QueuePos::Tail, qe)).unwrap();
-                                rouille::Response::from_data(
-                                    "application/json",
-                                    serde::to_string(&Resp::success()).unwrap())
-                            } else {
-                                rouille::Response::from_data(
-                                    "application/json",
-                                    serde::to_string(&Resp::failure("file does not exist")).unwrap()
-                                ).with_status_code(400)
-                            }
-                        }
-                        Ok(None) => {
-                            rouille::Response::from_data(
-                                "application/json",
-                                serde::to_string(&Resp::failure("blob must contain path!")).unwrap()
-                            ).with_status_code(400)
-                        }
-                        Err(_) => {
-                            rouille::Response::from_data(
-                                "application/json",
-                                serde::to_string(&Resp::failure("malformed json sent")).unwrap()
-                            ).with_status_code(400)
-                        }
-                    }
+                    self.with_target(req, |t| self.insert(req, t, QueuePos::Tail))
                 },
 
                 (DELETE) (/queue/tail) => {
                     debug!("Handling queue tail remove");
-                    self.chan.lock().unwrap().send(ApiMessage::Remove(QueuePos::Tail)).unwrap();
-                    rouille::Response::from_data(
-                        "application/json",
-                        serde::to_string(&Resp::success()).unwrap())
+                    self.with_target(req, |t| send(t, ApiMessage::Remove(QueuePos::Tail)))
                 },
 
                 (POST) (/skip) => {
                     debug!("Handling queue skip");
-                    self.chan.lock().unwrap().send(ApiMessage::Skip).unwrap();
-                    rouille::Response::from_data(
-                        "application/json",
-                        serde::to_string(&Resp::success()).unwrap())
+                    self.with_target(req, |t| send(t, ApiMessage::Skip))
                 },
 
                 (POST) (/queue/clear) => {
                     debug!("Handling queue clear");
-                    self.chan.lock().unwrap().send(ApiMessage::Clear).unwrap();
+                    self.with_target(req, |t| send(t, ApiMessage::Clear))
+                },
+
+                (GET) (/mounts) => {
+                    debug!("Handling mounts req");
+                    let mut mounts: Vec<_> = self.targets().iter()
+                        .flat_map(|t| t.queue.lock().unwrap().streams().iter().map(Mount::new).collect::<Vec<_>>())
+                        .collect();
+                    mounts.sort_by_key(|m| m.id);
+                    rouille::Response::from_data(
+                        "application/json",
+                        serde::to_string(&mounts).unwrap())
+                },
+
+                (POST) (/mounts) => {
+                    debug!("Handling mount update");
+                    self.update_mount(req)
+                },
+
+                (POST) (/channels) => {
+                    debug!("Handling channel start");
+                    self.start_channel(req)
+                },
+
+                (POST) (/cue) => {
+                    debug!("Handling cue injection");
+                    self.cue(req)
+                },
+
+                (GET) (/config) => {
+                    debug!("Handling config req");
+                    self.config()
+                },
+
+                (GET) (/debug/stats) => {
+                    debug!("Handling debug stats req");
+                    self.with_target(req, |t| {
+                        let q = t.queue.lock().unwrap();
+                        let stats = DebugStats {
+                            np: TrackStats::new(q.np()),
+                            next: TrackStats::new(q.next()),
+                            errors: self.errors.current(),
+                        };
+                        rouille::Response::from_data(
+                            "application/json",
+                            serde::to_string(&stats).unwrap())
+                    })
+                },
+
+                (GET) (/debug/threads) => {
+                    debug!("Handling debug threads req");
+                    let (writers, readers) = tc_queue::live();
+                    let threads = DebugThreads {
+                        transcoders: self.transcodes(),
+                        live_writers: writers,
+                        live_readers: readers,
+                    };
                     rouille::Response::from_data(
                         "application/json",
-                        serde::to_string(&Resp::success()).unwrap())
+                        serde::to_string(&threads).unwrap())
+                },
+
+                (GET) (/debug/graph) => {
+                    debug!("Handling debug graph req");
+                    let graphs: Vec<_> = self.transcodes().into_iter()
+                        .map(DebugGraph::new)
+                        .collect();
+                    rouille::Response::from_data(
+                        "application/json",
+                        serde::to_string(&graphs).unwrap())
+                },
+
+                (POST) (/debug/faults) => {
+                    debug!("Handling fault injection");
+                    match req.data().map(|d| serde::from_reader::<_, Faults>(d)) {
+                        Some(Ok(f)) => match faults::inject(&f) {
+                            Ok(()) => Resp::success().into_response(),
+                            Err(e) => Resp::error(&e),
+                        },
+                        _ => Resp::error(&Error::BadRequest(format!("malformed json sent"))),
+                    }
+                },
+
+                (GET) (/events) => {
+                    debug!("Handling events req");
+                    let since = req.get_param("since").and_then(|s| s.parse().ok()).unwrap_or(0);
+                    rouille::Response::from_data(
+                        "application/json",
+                        serde::to_string(&self.events.since(since)).unwrap())
+                },
+
+                (GET) (/reports/playout) => {
+                    debug!("Handling playout report req");
+                    self.with_target(req, |t| self.playout_report(req, t))
                 },
 
                 _ => rouille::Response::empty_404()
             )
     }
+
+    /// Runs f with the queue a request is about, picked by its ?queue= parameter
+    fn with_target<F: FnOnce(&Target) -> rouille::Response>(&self, req: &rouille::Request, f: F) -> rouille::Response {
+        let name = req.get_param("queue");
+        let target = self.queues.read().unwrap().get(&name).cloned();
+        match target {
+            Some(t) => f(&t),
+            None => Resp::error(&Error::BadRequest(format!("there is no queue named {}",
+                                                           name.as_ref().map(|n| &**n).unwrap_or("")))),
+        }
+    }
+
+    /// All queues, including the channels started so far
+    fn targets(&self) -> Vec<Target> {
+        self.queues.read().unwrap().values().cloned().collect()
+    }
+
+    /// Transcodes which are alive across all queues
+    fn transcodes(&self) -> Vec<TranscodeInfo> {
+        self.targets().iter()
+            .flat_map(|t| t.queue.lock().unwrap().transcodes().live())
+            .collect()
+    }
+
+    /// Enqueues the track in the request body. Requests carrying an Idempotency-Key which was
+    /// already used successfully within the window are acknowledged without queueing it again.
+    fn insert(&self, req: &rouille::Request, t: &Target, pos: QueuePos) -> rouille::Response {
+        let key = match req.header("Idempotency-Key") {
+            Some(k) => k.to_owned(),
+            None => return self.enqueue(req, t, pos),
+        };
+        let endpoint = match pos {
+            QueuePos::Head => "/queue/head",
+            QueuePos::Tail => "/queue/tail",
+        };
+        self.idempotency_keys.once((t.name.clone(), endpoint, key), || self.enqueue(req, t, pos))
+    }
+
+    fn enqueue(&self, req: &rouille::Request, t: &Target, pos: QueuePos) -> rouille::Response {
+        let qe = match req.data().map(|d| serde::from_reader::<_, JSON>(d)) {
+            Some(Ok(d)) => match NewQueueEntry::deserialize(d) {
+                Some(qe) => qe,
+                None => return Resp::error(&Error::BadRequest(format!("blob must contain path!"))),
+            },
+            _ => return Resp::error(&Error::BadRequest(format!("malformed json sent"))),
+        };
+        if let Err(e) = fs::metadata(&qe.path) {
+            return Resp::error(&Error::FileMissing(qe.path, e));
+        }
+        if let Some(reason) = self.cfg.blocklist.check(&qe.path, &qe.data) {
+            return Resp::error(&Error::Blocked(reason));
+        }
+        send(t, ApiMessage::Insert(pos, qe))
+    }
+
+    fn update_mount(&self, req: &rouille::Request) -> rouille::Response {
+        let update = match req.data().map(|d| serde::from_reader::<_, MountUpdate>(d)) {
+            Some(Ok(u)) => u,
+            _ => return Resp::error(&Error::BadRequest(format!("malformed json sent"))),
+        };
+        if update.bitrate.map(|b| b <= 0).unwrap_or(false) {
+            return Resp::error(&Error::BadRequest(format!("bitrate must be positive")));
+        }
+        let codec = match update.codec.as_ref().map(|c| config::parse_codec(c)) {
+            Some(Ok(c)) => Some(c),
+            Some(Err(e)) => return Resp::error(&e),
+            None => None,
+        };
+        for t in self.targets().iter() {
+            match set_encoding(t, &update, codec) {
+                Ok(true) => return Resp::success().into_response(),
+                Ok(false) => { }
+                Err(e) => return Resp::error(&e),
+            }
+        }
+        Resp::error(&Error::BadRequest(format!("there is no mount {}", update.mount)))
+    }
+
+    /// Puts a channel playing the library tracks matching a filter on air, on copies of the default
+    /// queue's mounts
+    fn start_channel(&self, req: &rouille::Request) -> rouille::Response {
+        let new = match req.data().map(|d| serde::from_reader::<_, NewChannel>(d)) {
+            Some(Ok(c)) => c,
+            _ => return Resp::error(&Error::BadRequest(format!("malformed json sent"))),
+        };
+        let (queue, updates) = match self.channels.start(&new.name, new.filter) {
+            Ok(c) => c,
+            Err(e) => return Resp::error(&e),
+        };
+        let target = Target {
+            name: Some(new.name.clone()),
+            queue,
+            chan: Arc::new(Mutex::new(updates)),
+            history: self.cfg.queue.history.clone(),
+        };
+        self.queues.write().unwrap().insert(Some(new.name), target);
+        Resp::success().into_response()
+    }
+
+    fn cue(&self, req: &rouille::Request) -> rouille::Response {
+        let name = match req.data().map(|d| serde::from_reader::<_, JSON>(d)) {
+            Some(Ok(d)) => match d.get("name").and_then(|n| n.as_str()) {
+                Some(n) => n.to_owned(),
+                None => return Resp::error(&Error::BadRequest(format!("blob must contain name!"))),
+            },
+            _ => return Resp::error(&Error::BadRequest(format!("malformed json sent"))),
+        };
+        if !self.cfg.streams.iter().any(|s| s.cues) {
+            return Resp::error(&Error::BadRequest(format!("no stream has cues enabled")));
+        }
+        // Every radio loop injects the cue into its own mounts
+        for t in self.targets().iter() {
+            if t.chan.lock().unwrap().send(ApiMessage::Cue(name.clone())).is_err() {
+                return Resp::error(&Error::Unavailable);
+            }
+        }
+        Resp::success().into_response()
+    }
+
+    fn config(&self) -> rouille::Response {
+        let mut cfg = serde::to_value(self.cfg.redacted()).unwrap();
+        // Mounts may have been changed through the API since startup
+        for t in self.targets().iter() {
+            for s in t.queue.lock().unwrap().streams() {
+                if let Some(m) = cfg.pointer_mut(&format!("/streams/{}", s.id)).and_then(|m| m.as_object_mut()) {
+                    match s.bitrate {
+                        Some(b) => m.insert("bitrate".to_owned(), serde::to_value(b).unwrap()),
+                        None => m.remove("bitrate"),
+                    };
+                    m.insert("codec".to_owned(), serde::to_value(config::codec_name(s.codec)).unwrap());
+                }
+            }
+        }
+        rouille::Response::from_data("application/json", serde::to_string(&cfg).unwrap())
+    }
+
+    fn playout_report(&self, req: &rouille::Request, t: &Target) -> rouille::Response {
+        let path = match t.history {
+            Some(ref p) => p,
+            None => return Resp::error(&Error::BadRequest(format!("no history is configured"))),
+        };
+        let date = |param: &str| match req.get_param(param) {
+            Some(d) => util::parse_date(&d)
+                .ok_or_else(|| Error::BadRequest(format!("{} must be a date of the form YYYY-MM-DD", param))),
+            None => Err(Error::BadRequest(format!("{} is required", param))),
+        };
+        // Both dates are inclusive
+        let (from, to) = match (date("from"), date("to")) {
+            (Ok(from), Ok(to)) => (from, to + 86400),
+            (Err(e), _) | (_, Err(e)) => return Resp::error(&e),
+        };
+        let plays = match history::load(path, from, to) {
+            Ok(p) => p,
+            Err(e) => return Resp::error(&e),
+        };
+        match req.get_param("format").as_ref().map(|f| &**f) {
+            None | Some("csv") => {
+                rouille::Response::from_data("text/csv", history::csv(&plays))
+            }
+            Some("soundexchange") => {
+                rouille::Response::from_data("text/tab-separated-values",
+                                             history::soundexchange(&self.cfg.radio.name, &plays))
+            }
+            Some(f) => Resp::error(&Error::BadRequest(format!("unknown report format {}", f))),
+        }
+    }
+
+}
+
+/// Changes the encoding of a mount, returning false if the queue doesn't play it. The fallback is
+/// encoded with the new settings before taking the lock, so the radio loop isn't held up meanwhile.
+fn set_encoding(t: &Target, update: &MountUpdate, codec: Option<kaeru::AVCodecID>) -> Result<bool> {
+    loop {
+        let settings = t.queue.lock().unwrap().settings();
+        let enc = match Encoding::prepare(settings, &update.mount, update.bitrate, codec)? {
+            Some(e) => e,
+            None => return Ok(false),
+        };
+        // Another change which came in meanwhile would be undone otherwise
+        if t.queue.lock().unwrap().set_encoding(enc) {
+            return Ok(true);
+        }
+    }
+}
+
+impl IdempotencyKeys {
+    fn new(window: u64) -> IdempotencyKeys {
+        IdempotencyKeys {
+            window: time::Duration::from_secs(window),
+            seen: Mutex::new(HashMap::new()),
+            done: Condvar::new(),
+        }
+    }
+
+    /// Runs the request unless one with the same key already succeeded within the window, in
+    /// which case it's acknowledged without running it again
+    fn once<F>(&self, key: IdempotencyKey, f: F) -> rouille::Response
+        where F: FnOnce() -> rouille::Response
+    {
+        {
+            let mut seen = self.seen.lock().unwrap();
+            let window = self.window;
+            loop {
+                seen.retain(|_, s| match *s {
+                    Seen::Running => true,
+                    Seen::Succeeded(t) => t.elapsed() < window,
+                });
+                let succeeded = seen.get(&key).map(|s| match *s {
+                    Seen::Running => false,
+                    Seen::Succeeded(_) => true,
+                });
+                match succeeded {
+                    Some(true) => {
+                        debug!("Ignoring repeated request with idempotency key {}", key.2);
+                        return Resp::success().into_response();
+                    }
+                    // A retry racing the original waits for its outcome, requests with other keys
+                    // go ahead meanwhile
+                    Some(false) => seen = self.done.wait(seen).unwrap(),
+                    None => break,
+                }
+            }
+            seen.insert(key.clone(), Seen::Running);
+        }
+        let mut running = Running { keys: self, key, succeeded: false };
+        let resp = f();
+        running.succeeded = resp.status_code == 200;
+        resp
+    }
+}
+
+impl<'a> Drop for Running<'a> {
+    fn drop(&mut self) {
+        let mut seen = self.keys.seen.lock().unwrap();
+        if self.succeeded {
+            seen.insert(self.key.clone(), Seen::Succeeded(time::Instant::now()));
+        } else {
+            seen.remove(&self.key);
+        }
+        self.keys.done.notify_all();
+    }
+}
+
+fn send(t: &Target, msg: ApiMessage) -> rouille::Response {
+    match t.chan.lock().unwrap().send(msg) {
+        Ok(()) => Resp::success().into_response(),
+        Err(_) => Resp::error(&Error::Unavailable),
+    }
+}
+
+impl Mount {
+    fn new(s: &config::StreamConfig) -> Mount {
+        Mount {
+            id: s.id,
+            mount: s.mount.clone(),
+            container: s.container.as_str(),
+            codec: config::codec_name(s.codec),
+            bitrate: s.bitrate,
+            surround: s.surround,
+            queue: s.queue.clone(),
+        }
+    }
+}
+
+impl TrackStats {
+    fn new(qb: &QueueBuffer) -> TrackStats {
+        TrackStats {
+            id: qb.entry().id,
+            path: qb.entry().path.clone(),
+            input_offset: qb.input().offset(),
+            input_length: qb.input().length(),
+        }
+    }
+}
+
+impl DebugGraph {
+    fn new(t: TranscodeInfo) -> DebugGraph {
+        DebugGraph {
+            transcode: t.id,
+            entry: t.entry,
+            path: t.path,
+            input: DebugStream::new(&t.graph.input),
+            filters: t.graph.filters.iter()
+                .map(|f| DebugFilter { name: f.name.clone(), filter: f.filter.clone(), inputs: f.inputs.clone() })
+                .collect(),
+            outputs: t.graph.outputs.iter().map(DebugStream::new).collect(),
+        }
+    }
+}
+
+impl DebugStream {
+    fn new(s: &kaeru::StreamInfo) -> DebugStream {
+        DebugStream {
+            format: s.format.clone(),
+            codec: s.codec.clone(),
+            sample_rate: s.sample_rate,
+            sample_fmt: s.sample_fmt.clone(),
+            channels: s.channels,
+            channel_layout: format!("0x{:X}", s.channel_layout),
+            bit_rate: s.bit_rate,
+            frame_size: s.frame_size,
+        }
+    }
 }
 
 impl Resp {
@@ -185,30 +658,120 @@ impl Resp {
         Resp {
             success: true,
             reason: None,
+            error: None,
         }
     }
 
-    fn failure(reason: &str) -> Resp {
+    fn error(e: &Error) -> rouille::Response {
+        let status = match *e {
+            Error::Blocked(_) => 403,
+            Error::Unavailable => 503,
+            _ => 400,
+        };
         Resp {
             success: false,
-            reason: Some(String::from(reason)),
-        }
+            reason: Some(e.to_string()),
+            error: Some(ErrorInfo { code: e.code(), category: e.category() }),
+        }.into_response().with_status_code(status)
+    }
 
+    fn into_response(self) -> rouille::Response {
+        rouille::Response::from_data("application/json", serde::to_string(&self).unwrap())
     }
 }
 
 
-pub fn start_api(config: ApiConfig, queue: Arc<Mutex<Queue>>, listeners: Listeners, updates: Sender<ApiMessage>) {
+/// Starts the API for the given queues, None being the name of the default one. Each comes with
+/// the channel to the radio loop playing it.
+pub fn start_api(config: Config,
+                 queues: Vec<(Option<String>, SQueue, Sender<ApiMessage>)>,
+                 listeners: Listeners,
+                 events: Events,
+                 errors: ErrLog,
+                 channels: Channels) {
     thread::spawn(move || {
         info!("Starting API");
-        let chan = Arc::new(Mutex::new(updates));
+        let (host, port) = (config.api.host.clone(), config.api.port);
+        let queues = queues.into_iter()
+            .map(|(name, queue, updates)| {
+                let history = match name {
+                    Some(ref n) => config.queues[n].history.clone(),
+                    None => config.queue.history.clone(),
+                };
+                (name.clone(), Target { name, queue, chan: Arc::new(Mutex::new(updates)), history })
+            })
+            .collect();
+        let idempotency_keys = IdempotencyKeys::new(config.api.idempotency_window);
         let serv = Server {
-            queue: queue,
-            chan: chan,
+            cfg: config,
+            queues: RwLock::new(queues),
             listeners,
+            events,
+            errors,
+            idempotency_keys,
+            channels,
         };
-        rouille::start_server(("127.0.0.1", config.port), move |request| {
+        rouille::start_server((&*host, port), move |request| {
             serv.handle_request(request)
         });
     });
 }
+
+#[test]
+fn test_idempotency_keys() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+
+    let keys = Arc::new(IdempotencyKeys::new(600));
+    let key = |queue: Option<&str>, endpoint, k: &str| (queue.map(|q| q.to_owned()), endpoint, k.to_owned());
+    let runs = Arc::new(AtomicUsize::new(0));
+    let run = || {
+        runs.fetch_add(1, Ordering::SeqCst);
+        Resp::success().into_response()
+    };
+    // Failed requests may be retried
+    let failed = keys.once(key(None, "/queue/tail", "a"),
+                           || Resp::error(&Error::BadRequest(format!("malformed json sent"))));
+    assert!(failed.status_code != 200);
+    assert_eq!(keys.once(key(None, "/queue/tail", "a"), &run).status_code, 200);
+    assert_eq!(runs.load(Ordering::SeqCst), 1);
+    assert_eq!(keys.once(key(None, "/queue/tail", "a"), &run).status_code, 200);
+    assert_eq!(runs.load(Ordering::SeqCst), 1);
+    // The same key on another endpoint or queue is a different request
+    keys.once(key(None, "/queue/head", "a"), &run);
+    keys.once(key(Some("night"), "/queue/tail", "a"), &run);
+    assert_eq!(runs.load(Ordering::SeqCst), 3);
+
+    // A retry arriving while the original is still running waits for it, rather than running too
+    let (tx, rx) = mpsc::channel();
+    let (k, r) = (keys.clone(), runs.clone());
+    let original = thread::spawn(move || {
+        k.once(key(None, "/queue/tail", "b"), || {
+            r.fetch_add(1, Ordering::SeqCst);
+            tx.send(()).unwrap();
+            thread::sleep(time::Duration::from_millis(100));
+            Resp::success().into_response()
+        })
+    });
+    rx.recv().unwrap();
+    assert_eq!(keys.once(key(None, "/queue/tail", "b"), &run).status_code, 200);
+    assert_eq!(original.join().unwrap().status_code, 200);
+    assert_eq!(runs.load(Ordering::SeqCst), 4);
+
+    // Requests with other keys don't wait for a running one
+    let (started_tx, started) = mpsc::channel();
+    let (release, release_rx) = mpsc::channel::<()>();
+    let k = keys.clone();
+    let slow = thread::spawn(move || {
+        k.once(key(None, "/queue/tail", "c"), || {
+            started_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
+            Resp::success().into_response()
+        })
+    });
+    started.recv().unwrap();
+    assert_eq!(keys.once(key(None, "/queue/tail", "d"), &run).status_code, 200);
+    assert_eq!(runs.load(Ordering::SeqCst), 5);
+    release.send(()).unwrap();
+    assert_eq!(slow.join().unwrap().status_code, 200);
+}
@@ -0,0 +1,115 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{fs, io, time};
+#[cfg(unix)]
+use std::mem;
+
+use kaeru;
+#[cfg(unix)]
+use libc;
+
+use config::{Config, StreamConfig};
+use error::{Error, Result};
+use queue;
+use util;
+
+/// Counts the encoded bytes of an output and throws them away
+struct Counter(Arc<AtomicUsize>);
+
+struct Report {
+    duration: f64,
+    wall: f64,
+    /// None on platforms without a CPU clock
+    cpu: Option<f64>,
+    bytes: usize,
+}
+
+impl io::Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.fetch_add(buf.len(), Ordering::Relaxed);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+/// Transcodes `path` through each configured stream's graph as fast as possible, printing the
+/// realtime factor and CPU usage of each.
+pub fn run(cfg: &Config, path: &str) -> Result<()> {
+    println!("{:<24} {:>10} {:>10} {:>8} {:>10}", "mount", "realtime", "cpu", "wall", "kbit/s");
+    let mut total_wall = 0.;
+    let mut total_cpu = Some(0.);
+    for s in cfg.streams.iter() {
+        let r = bench_stream(cfg, s, path)?;
+        total_wall += r.wall;
+        total_cpu = total_cpu.and_then(|t| r.cpu.map(|c| t + c));
+        println!("{:<24} {:>9.1}x {:>10} {:>7.2}s {:>10.1}",
+                 s.mount,
+                 r.duration / r.wall,
+                 percent(r.cpu, r.wall),
+                 r.wall,
+                 r.bytes as f64 * 8. / 1000. / r.duration);
+    }
+    match total_cpu {
+        Some(cpu) => println!("All mounts together need {:.1}% of one core per {:.2}s of transcode time",
+                              cpu / total_wall * 100., total_wall),
+        None => println!("All mounts together took {:.2}s, CPU usage isn't available on this platform", total_wall),
+    }
+    Ok(())
+}
+
+fn bench_stream(cfg: &Config, s: &StreamConfig, path: &str) -> Result<Report> {
+    let f = fs::File::open(path).map_err(|e| Error::FileMissing(path.to_owned(), e))?;
+    let ext = util::container_ext(path)
+        .ok_or_else(|| Error::Unsupported(format!("{} has no container extension", path)))?;
+    let input = kaeru::Input::new(f, ext)?;
+    let duration = input.duration();
+    let bytes = Arc::new(AtomicUsize::new(0));
+    let mut output = kaeru::Output::new_writer(Counter(bytes.clone()), s.container.as_str(), s.codec, s.bitrate)?;
+    output.set_surround(s.surround);
+    let mut gb = queue::graph(input, cfg)?;
+    gb.add_output(output)?;
+    let g = gb.build()?;
+
+    let cpu_start = cpu_time();
+    let start = time::Instant::now();
+    g.run()?;
+    let wall = secs(start.elapsed());
+    let cpu = cpu_start.and_then(|s| cpu_time().map(|e| e - s));
+
+    Ok(Report {
+        duration: secs(duration),
+        wall,
+        cpu,
+        bytes: bytes.load(Ordering::Relaxed),
+    })
+}
+
+fn percent(cpu: Option<f64>, wall: f64) -> String {
+    match cpu {
+        Some(c) => format!("{:.1}%", c / wall * 100.),
+        None => "n/a".to_owned(),
+    }
+}
+
+fn secs(d: time::Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9
+}
+
+/// User + system CPU time consumed by the process, in seconds
+#[cfg(unix)]
+fn cpu_time() -> Option<f64> {
+    unsafe {
+        let mut usage: libc::rusage = mem::zeroed();
+        if libc::getrusage(libc::RUSAGE_SELF, &mut usage) != 0 {
+            return None;
+        }
+        let tv = |t: libc::timeval| t.tv_sec as f64 + t.tv_usec as f64 / 1e6;
+        Some(tv(usage.ru_utime) + tv(usage.ru_stime))
+    }
+}
+
+#[cfg(not(unix))]
+fn cpu_time() -> Option<f64> {
+    None
+}
@@ -0,0 +1,163 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::Map;
+use serde_json::Value as JSON;
+
+/// Tracks which must not be played, checked when entries are enqueued through the API and when
+/// picking random entries.
+#[derive(Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Blocklist {
+    /// Path patterns, e.g. "/music/holiday/*"
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// Values of the "id" field of track blobs
+    #[serde(default)]
+    pub ids: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<TagRule>,
+    pub daypart: Option<Daypart>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TagRule {
+    /// Field of the track blob to match on
+    pub field: String,
+    /// Pattern the field's value is matched against, case insensitively
+    pub pattern: String,
+    /// If set, matching tracks are only blocked outside of the daypart window
+    #[serde(default)]
+    pub daypart: bool,
+}
+
+/// Window of hours during which tracks matching daypart rules may air. The window may wrap
+/// around midnight, e.g. 22 to 6.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Daypart {
+    pub start: u8,
+    pub end: u8,
+    /// Offset of local time from UTC in hours
+    #[serde(default)]
+    pub utc_offset: i8,
+}
+
+impl Blocklist {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.tags.iter().any(|t| t.daypart) && self.daypart.is_none() {
+            return Err(format!("blocklist tags use daypart, but no [blocklist.daypart] is configured"));
+        }
+        if let Some(ref d) = self.daypart {
+            if d.start > 23 || d.end > 24 {
+                return Err(format!("blocklist daypart hours must be between 0 and 24"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the reason the track may not be played right now, if it is blocked
+    pub fn check(&self, path: &str, data: &Map<String, JSON>) -> Option<String> {
+        let hour = self.daypart.as_ref().map(|d| d.local_hour()).unwrap_or(0);
+        self.check_at(path, data, hour)
+    }
+
+    fn check_at(&self, path: &str, data: &Map<String, JSON>, hour: u8) -> Option<String> {
+        if let Some(p) = self.paths.iter().find(|p| glob_match(p, path)) {
+            return Some(format!("path matches blocked pattern {}", p));
+        }
+        if let Some(id) = data.get("id").map(json_str) {
+            if self.ids.iter().any(|i| *i == id) {
+                return Some(format!("id {} is blocked", id));
+            }
+        }
+        for rule in self.tags.iter() {
+            let value = match data.get(&rule.field) {
+                Some(v) => json_str(v),
+                None => continue,
+            };
+            if !glob_match(&rule.pattern.to_lowercase(), &value.to_lowercase()) {
+                continue;
+            }
+            match (rule.daypart, self.daypart.as_ref()) {
+                (true, Some(d)) if d.contains(hour) => { }
+                (true, _) => return Some(format!("{} {} may only air during the daypart window", rule.field, value)),
+                (false, _) => return Some(format!("{} {} is blocked", rule.field, value)),
+            }
+        }
+        None
+    }
+}
+
+impl Daypart {
+    fn contains(&self, hour: u8) -> bool {
+        if self.start <= self.end {
+            hour >= self.start && hour < self.end
+        } else {
+            hour >= self.start || hour < self.end
+        }
+    }
+
+    fn local_hour(&self) -> u8 {
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let hour = (secs / 3600 % 24) as i64 + self.utc_offset as i64;
+        ((hour % 24 + 24) % 24) as u8
+    }
+}
+
+fn json_str(v: &JSON) -> String {
+    match *v {
+        JSON::String(ref s) => s.clone(),
+        ref v => v.to_string(),
+    }
+}
+
+/// Matches text against a pattern in which `*` matches any sequence of characters
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    if !text.starts_with(first) {
+        return false;
+    }
+    let mut rest = &text[first.len()..];
+    let parts: Vec<_> = parts.collect();
+    match parts.split_last() {
+        // No wildcard at all, must be an exact match
+        None => rest.is_empty(),
+        Some((last, middle)) => {
+            for part in middle {
+                match rest.find(part) {
+                    Some(i) => rest = &rest[i + part.len()..],
+                    None => return false,
+                }
+            }
+            rest.ends_with(last)
+        }
+    }
+}
+
+#[test]
+fn test_glob_match() {
+    assert!(glob_match("/music/bad/*", "/music/bad/a.flac"));
+    assert!(glob_match("*.mp3", "/music/a.mp3"));
+    assert!(glob_match("true", "true"));
+    assert!(glob_match("*live*", "song (live) 2017"));
+    assert!(!glob_match("true", "true story"));
+    assert!(!glob_match("/music/bad/*", "/music/good/a.flac"));
+    assert!(!glob_match("a*b*c", "acb"));
+}
+
+#[test]
+fn test_daypart() {
+    let mut data = Map::new();
+    data.insert("explicit".to_owned(), JSON::Bool(true));
+    let bl = Blocklist {
+        tags: vec![TagRule { field: "explicit".to_owned(), pattern: "true".to_owned(), daypart: true }],
+        daypart: Some(Daypart { start: 22, end: 6, utc_offset: 0 }),
+        ..Default::default()
+    };
+    assert!(bl.check_at("/a.flac", &data, 12).is_some());
+    assert!(bl.check_at("/a.flac", &data, 23).is_none());
+    assert!(bl.check_at("/a.flac", &data, 3).is_none());
+    assert!(bl.check_at("/a.flac", &Map::new(), 12).is_none());
+}
@@ -1,28 +1,37 @@
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::net::{TcpStream, TcpListener, Ipv4Addr};
+use std::sync::mpsc;
 use std::{time, thread, cmp};
 use std::io::{self, Read, Write};
 
-use {amy, httparse};
+use httparse;
+use poll;
 use url::Url;
 
 use api;
-use config::{Config, StreamConfig, Container};
+use config::{Config, StreamConfig, Container, TimeshiftConfig};
+use error::{self, Error};
+use events::{Event, Events};
+use timeshift;
+use icy::{self, Icy};
+use util;
 
 const CLIENT_BUFFER_LEN: usize = 16384;
 // Number of frames to buffer by
 const BACK_BUFFER_LEN: usize = 256;
 // Seconds of inactivity until client timeout
-const CLIENT_TIMEOUT: u64 = 10;
+pub const CLIENT_TIMEOUT: u64 = 10;
 
 const CHUNK_SIZE: usize = 1024;
 static CHUNK_HEADER: &'static str = "400\r\n";
 static CHUNK_FOOTER: &'static str = "\r\n";
 
 pub struct Broadcaster {
-    poll: amy::Poller,
-    reg: amy::Registrar,
-    data: amy::Receiver<Buffer>,
+    poll: poll::Poller,
+    reg: poll::Registrar,
+    data: poll::Receiver<Buffer>,
+    /// Mounts added at runtime, numbered on from the configured ones
+    mounts: poll::Receiver<StreamConfig>,
     /// Map of amy ID -> incoming client
     incoming: HashMap<usize, Incoming>,
     /// Map from amy ID -> client
@@ -33,15 +42,19 @@ pub struct Broadcaster {
     client_mounts: Vec<HashSet<usize>>,
     listener: TcpListener,
     listeners: api::Listeners,
+    events: Events,
     lid: usize,
     tid: usize,
     name: String,
+    /// Channel to the timeshift recorder, if any mount is recorded
+    recorder: Option<mpsc::Sender<Buffer>>,
+    timeshift: Option<TimeshiftConfig>,
 }
 
 #[derive(Clone, Debug)]
 pub struct Buffer {
-    mount: usize,
-    data: BufferData
+    pub mount: usize,
+    pub data: BufferData
 }
 
 #[derive(Clone, Debug)]
@@ -49,6 +62,10 @@ pub enum BufferData {
     Header(Vec<u8>),
     Frame { data: Vec<u8>, pts: f64 },
     Trailer(Vec<u8>),
+    /// Title of the track which starts playing next
+    Title(String),
+    /// In-band marker for downstream automation
+    Cue(String),
 }
 
 struct Client {
@@ -57,6 +74,9 @@ struct Client {
     last_action: time::Instant,
     agent: Agent,
     chunker: Chunker,
+    icy: Option<Icy>,
+    /// Bytes of the stream written to the connection
+    sent: u64,
 }
 
 #[derive(PartialEq)]
@@ -75,11 +95,12 @@ struct Incoming {
 
 struct Stream {
     config: StreamConfig,
+    title: String,
     header: Vec<u8>,
     buffer: VecDeque<Vec<u8>>,
 }
 
-enum Chunker {
+pub enum Chunker {
     Header(&'static str),
     Body(usize),
     Footer(&'static str),
@@ -93,40 +114,54 @@ enum WR {
     Err,
 }
 
-pub fn start(cfg: &Config, listeners: api::Listeners) -> amy::Sender<Buffer> {
-    let (mut b, tx) = Broadcaster::new(cfg, listeners).unwrap();
+/// Starts the broadcaster, returning the channels for the output of the mounts and for adding
+/// mounts
+pub fn start(cfg: &Config, listeners: api::Listeners, events: Events)
+             -> error::Result<(poll::Sender<Buffer>, poll::Sender<StreamConfig>)> {
+    let (mut b, tx, mtx) = Broadcaster::new(cfg, listeners, events).map_err(Error::Socket)?;
     thread::spawn(move || b.run());
-    tx
+    Ok((tx, mtx))
 }
 
 impl Broadcaster {
-    pub fn new(cfg: &Config, listeners: api::Listeners) -> io::Result<(Broadcaster, amy::Sender<Buffer>)> {
-        let poll = amy::Poller::new()?;
+    pub fn new(cfg: &Config, listeners: api::Listeners, events: Events)
+               -> io::Result<(Broadcaster, poll::Sender<Buffer>, poll::Sender<StreamConfig>)> {
+        let poll = poll::Poller::new()?;
         let mut reg = poll.get_registrar()?;
         let listener = TcpListener::bind((Ipv4Addr::new(0, 0, 0, 0), cfg.radio.port))?;
         listener.set_nonblocking(true)?;
-        let lid = reg.register(&listener, amy::Event::Read)?;
+        let lid = reg.register(&listener, poll::Event::Read)?;
         let tid = reg.set_interval(5000)?;
         let (tx, rx) = reg.channel()?;
+        let (mtx, mrx) = reg.channel()?;
         let mut streams = Vec::new();
         for config in cfg.streams.iter().cloned() {
-            streams.push(Stream { config, header: Vec::new(), buffer: VecDeque::with_capacity(BACK_BUFFER_LEN) })
+            streams.push(Stream {
+                config,
+                title: String::new(),
+                header: Vec::new(),
+                buffer: VecDeque::with_capacity(BACK_BUFFER_LEN),
+            })
         }
 
         Ok((Broadcaster {
             poll,
             reg,
             data: rx,
+            mounts: mrx,
             incoming: HashMap::new(),
             clients: HashMap::new(),
             streams,
             client_mounts: vec![HashSet::new(); cfg.streams.len()],
             listener,
             listeners,
+            events,
             lid,
             tid,
             name: cfg.radio.name.clone(),
-        }, tx))
+            recorder: timeshift::start(cfg),
+            timeshift: cfg.timeshift.clone(),
+        }, tx, mtx))
     }
 
     pub fn run(&mut self) {
@@ -139,6 +174,8 @@ impl Broadcaster {
                     self.reap();
                 } else if n.id == self.data.get_id() {
                     self.process_buffer();
+                } else if n.id == self.mounts.get_id() {
+                    self.add_mounts();
                 } else if self.incoming.contains_key(&n.id) {
                     self.process_incoming(n.id);
                 } else if self.clients.contains_key(&n.id) {
@@ -170,6 +207,13 @@ impl Broadcaster {
         for id in ids.iter() {
             self.remove_client(id);
         }
+
+        let mut listeners = self.listeners.lock().unwrap();
+        for (id, client) in self.clients.iter() {
+            if let Some(l) = listeners.get_mut(id) {
+                l.bytes_sent = client.sent;
+            }
+        }
     }
 
     fn accept_client(&mut self) {
@@ -177,7 +221,7 @@ impl Broadcaster {
             match self.listener.accept() {
                 Ok((conn, ip)) => {
                     debug!("Accepted new connection from {:?}!", ip);
-                    let pid = self.reg.register(&conn, amy::Event::Read).unwrap();
+                    let pid = self.reg.register(&conn, poll::Event::Read).unwrap();
                     self.incoming.insert(pid, Incoming::new(conn));
                 }
                 Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
@@ -189,8 +233,49 @@ impl Broadcaster {
 
     }
 
+    fn add_mounts(&mut self) {
+        while let Ok(config) = self.mounts.try_recv() {
+            debug!("Adding mount {}", config.mount);
+            self.streams.push(Stream {
+                config,
+                title: String::new(),
+                header: Vec::new(),
+                buffer: VecDeque::with_capacity(BACK_BUFFER_LEN),
+            });
+            self.client_mounts.push(HashSet::new());
+        }
+    }
+
     fn process_buffer(&mut self) {
         while let Ok(buf) = self.data.try_recv() {
+            // A new mount is sent before its output, but their notifications may come in either
+            // order
+            if buf.mount >= self.streams.len() {
+                self.add_mounts();
+            }
+            match buf.data {
+                BufferData::Title(ref t) => {
+                    self.streams[buf.mount].title = t.clone();
+                    for id in self.client_mounts[buf.mount].iter() {
+                        if let Some(ref mut icy) = self.clients.get_mut(id).unwrap().icy {
+                            icy.set_title(t);
+                        }
+                    }
+                    continue;
+                }
+                BufferData::Cue(ref c) => {
+                    for id in self.client_mounts[buf.mount].iter() {
+                        if let Some(ref mut icy) = self.clients.get_mut(id).unwrap().icy {
+                            icy.cue(c);
+                        }
+                    }
+                    continue;
+                }
+                _ => { }
+            }
+            if let Some(ref r) = self.recorder {
+                r.send(buf.clone()).ok();
+            }
             for id in self.client_mounts[buf.mount].clone() {
                 if {
                     let client = self.clients.get_mut(&id).unwrap();
@@ -231,14 +316,36 @@ impl Broadcaster {
                     return;
                 };
                 let mount = url.path();
+                let shift = url.query_pairs()
+                    .find(|&(ref k, _)| k == "timeshift")
+                    .and_then(|(_, v)| v.parse::<u64>().ok());
 
                 let inc = self.incoming.remove(&id).unwrap();
                 for (mid, stream) in self.streams.iter().enumerate() {
-                    if mount.ends_with(&stream.config.mount) {
+                    // Mounts may be served below a prefix by a proxy, but chill-a.mp3 isn't a.mp3
+                    if mount.ends_with(&format!("/{}", stream.config.mount.trim_start_matches('/'))) {
+                        if let (Some(minutes), Some(window), Some(ts)) = (shift, stream.config.timeshift, self.timeshift.as_ref()) {
+                            if minutes > 0 {
+                                debug!("Adding a client to stream {} shifted by {} minutes", stream.config.mount, minutes);
+                                // Timeshifted clients are served from the recorded segments on
+                                // their own thread
+                                self.reg.deregister(&inc.conn).unwrap();
+                                timeshift::serve(inc.conn, self.name.clone(), stream.config.clone(), ts,
+                                                 cmp::min(minutes, window));
+                                return;
+                            }
+                        }
                         debug!("Adding a client to stream {}", stream.config.mount);
                         // Swap to write only mode
-                        self.reg.reregister(id, &inc.conn, amy::Event::Write).unwrap();
-                        let mut client = Client::new(inc.conn, agent);
+                        self.reg.reregister(id, &inc.conn, poll::Event::Write).unwrap();
+                        let wants_icy = headers.iter()
+                            .any(|h| h.name.eq_ignore_ascii_case("icy-metadata") && h.value.trim() == "1");
+                        let icy = if wants_icy && stream.config.cues {
+                            Some(Icy::new(&stream.title))
+                        } else {
+                            None
+                        };
+                        let mut client = Client::new(inc.conn, agent, icy);
                         // Send header, and buffered data
                         if client.write_resp(&self.name, &stream.config)
                             .and_then(|_| client.send_data(&stream.header))
@@ -253,10 +360,21 @@ impl Broadcaster {
                         {
                             self.client_mounts[mid].insert(id);
                             self.clients.insert(id, client);
+                            let user_agent = headers.iter()
+                                .find(|h| h.name.eq_ignore_ascii_case("user-agent"))
+                                .map(|h| h.value.clone());
+                            self.events.emit(Event::ListenerConnected {
+                                id,
+                                mount: stream.config.mount.clone(),
+                                user_agent: user_agent.clone(),
+                            });
                             self.listeners.lock().unwrap().insert(id, api::Listener {
                                 mount: stream.config.mount.clone(),
                                 path: path.clone(),
                                 headers,
+                                user_agent,
+                                connected: util::now(),
+                                bytes_sent: 0,
                             });
                         } else {
                             debug!("Failed to write data to client");
@@ -281,7 +399,15 @@ impl Broadcaster {
     fn remove_client(&mut self, id: &usize) {
         let client = self.clients.remove(id).unwrap();
         self.reg.deregister(&client.conn).unwrap();
-        self.listeners.lock().unwrap().remove(id);
+        let listener = self.listeners.lock().unwrap().remove(id);
+        if let Some(l) = listener {
+            self.events.emit(Event::ListenerDisconnected {
+                id: *id,
+                mount: l.mount,
+                duration: util::now().saturating_sub(l.connected),
+                bytes_sent: client.sent,
+            });
+        }
         // Remove from client_mounts map too
         for m in self.client_mounts.iter_mut() {
             m.remove(id);
@@ -308,11 +434,19 @@ impl BufferData {
         }
     }
 
+    pub fn is_header(&self) -> bool {
+        match *self {
+            BufferData::Header(_) => true,
+            _ => false,
+        }
+    }
+
     pub fn frame(&self) -> &[u8] {
         match *self {
             BufferData::Header(ref f)
             | BufferData::Frame { data: ref f, .. }
             | BufferData::Trailer(ref f) => f,
+            BufferData::Title(_) | BufferData::Cue(_) => &[],
         }
     }
 }
@@ -380,31 +514,20 @@ impl Incoming {
 }
 
 impl Client {
-    fn new(conn: TcpStream, agent: Agent) -> Client {
+    fn new(conn: TcpStream, agent: Agent, icy: Option<Icy>) -> Client {
         Client {
             conn,
             buffer: VecDeque::with_capacity(CLIENT_BUFFER_LEN),
             last_action: time::Instant::now(),
             chunker: Chunker::new(),
             agent,
+            icy,
+            sent: 0,
         }
     }
 
     fn write_resp(&mut self, name: &str, config: &StreamConfig) -> Result<(), ()> {
-        let lines = vec![
-            format!("HTTP/1.1 200 OK"),
-            format!("Server: {}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
-            format!("Content-Type: {}", if let Container::MP3 = config.container {
-                "audio/mpeg"
-            } else {
-                "application/ogg"
-            }),
-            format!("Transfer-Encoding: chunked"),
-            format!("Connection: keep-alive"),
-            format!("Cache-Control: no-cache"),
-            format!("x-audiocast-name: {}", name),
-        ];
-        let data = lines.join("\r\n") + "\r\n\r\n";
+        let data = response_header(name, config, self.icy.is_some());
         match self.conn.write(data.as_bytes()) {
             Ok(0) => Err(()),
             Ok(a) if a == data.as_bytes().len() => { Ok(() )}
@@ -431,13 +554,13 @@ impl Client {
             Err(()) => return Err(()),
         }
 
-        match self.chunker.write(&mut self.conn, data) {
+        match write_data(&mut self.icy, &mut self.chunker, &mut self.conn, &mut self.sent, data) {
             Ok(Some(0)) => Err(()),
             // Complete write, do nothing
             Ok(Some(a)) if a == data.len() => Ok(()),
-            // Incomplete write, append to buf
+            // Incomplete write, append the rest to buf
             Ok(Some(a)) => {
-                self.buffer.extend(data[0..a].iter());
+                self.buffer.extend(data[a..].iter());
                 while self.buffer.len() > CLIENT_BUFFER_LEN {
                     self.buffer.pop_front();
                 }
@@ -486,10 +609,11 @@ impl Client {
 
     fn write_buffer(&mut self) -> WR {
         let (head, tail) = self.buffer.as_slices();
-        match self.chunker.write(&mut self.conn, head) {
+        match write_data(&mut self.icy, &mut self.chunker, &mut self.conn, &mut self.sent, head) {
             Ok(Some(0)) => WR::Err,
+            Ok(Some(a)) if a == head.len() && tail.is_empty() => WR::Ok,
             Ok(Some(a)) if a == head.len() => {
-                match self.chunker.write(&mut self.conn, tail) {
+                match write_data(&mut self.icy, &mut self.chunker, &mut self.conn, &mut self.sent, tail) {
                     Ok(Some(0)) => WR::Err,
                     Ok(Some(i)) if i == tail.len() => WR::Ok,
                     Ok(Some(i)) => WR::Inc(i + a),
@@ -506,12 +630,60 @@ impl Client {
     }
 }
 
+/// Writes data to a client, interleaving ICY metadata if the client asked for it. sent is
+/// increased by the amount of data written.
+fn write_data(icy: &mut Option<Icy>, chunker: &mut Chunker, conn: &mut TcpStream, sent: &mut u64, data: &[u8])
+              -> io::Result<Option<usize>> {
+    let res = match *icy {
+        Some(ref mut icy) => icy.write(chunker, conn, data),
+        None => chunker.write(conn, data),
+    };
+    if let Ok(Some(a)) = res {
+        *sent += a as u64;
+    }
+    res
+}
+
+/// Builds the HTTP response header sent to listeners of a stream
+pub fn response_header(name: &str, config: &StreamConfig, icy: bool) -> String {
+    let mut lines = vec![
+        format!("HTTP/1.1 200 OK"),
+        format!("Server: {}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        format!("Content-Type: {}", if let Container::MP3 = config.container {
+            "audio/mpeg"
+        } else {
+            "application/ogg"
+        }),
+        format!("Transfer-Encoding: chunked"),
+        format!("Connection: keep-alive"),
+        format!("Cache-Control: no-cache"),
+        format!("x-audiocast-name: {}", name),
+    ];
+    if icy {
+        lines.push(format!("icy-name: {}", name));
+        lines.push(format!("icy-metaint: {}", icy::METAINT));
+    }
+    lines.join("\r\n") + "\r\n\r\n"
+}
+
 impl Chunker {
-    fn new() -> Chunker {
+    pub fn new() -> Chunker {
         Chunker::Header(CHUNK_HEADER)
     }
 
-    fn write<T: io::Write>(&mut self, conn: &mut T, data: &[u8]) -> io::Result<Option<usize>> {
+    /// Writes all of data to a blocking connection
+    pub fn write_all<T: io::Write>(&mut self, conn: &mut T, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            match self.write(conn, data)? {
+                Some(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "connection closed")),
+                Some(a) => data = &data[a..],
+                None => { }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write<T: io::Write>(&mut self, conn: &mut T, data: &[u8]) -> io::Result<Option<usize>> {
         match *self {
             Chunker::Header(s) => {
                 let amnt = conn.write(s.as_bytes())?;
@@ -570,3 +742,46 @@ fn test_footer_transition() {
     if let Chunker::Footer(" world") = c {
     } else { unreachable!() };
 }
+
+#[test]
+fn test_partial_write() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let conn = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let mut peer = listener.accept().unwrap().0;
+    conn.set_nonblocking(true).unwrap();
+    let mut client = Client::new(conn, Agent::Other, None);
+
+    // Write until the peer's window is full and the rest of a write is buffered. Writes are kept
+    // below CLIENT_BUFFER_LEN, so nothing is dropped.
+    let mut expected = Vec::new();
+    for i in 0..100000u32 {
+        let data: Vec<u8> = (0..1000u32).map(|j| (i * 7 + j) as u8).collect();
+        client.send_data(&data).unwrap();
+        expected.extend(data);
+        if !client.buffer.is_empty() {
+            break;
+        }
+    }
+    assert!(!client.buffer.is_empty());
+
+    let reader = thread::spawn(move || {
+        let mut out = Vec::new();
+        peer.read_to_end(&mut out).unwrap();
+        out
+    });
+    while !client.flush_buffer().unwrap() {
+        thread::sleep(time::Duration::from_millis(1));
+    }
+    drop(client);
+    // Strip the chunked encoding, the last chunk is cut short
+    let chunked = reader.join().unwrap();
+    let mut received = Vec::new();
+    let mut rest = &chunked[..];
+    while !rest.is_empty() {
+        rest = &rest[CHUNK_HEADER.len()..];
+        let n = cmp::min(CHUNK_SIZE, rest.len());
+        received.extend_from_slice(&rest[..n]);
+        rest = &rest[cmp::min(n + CHUNK_FOOTER.len(), rest.len())..];
+    }
+    assert!(received == expected);
+}
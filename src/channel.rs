@@ -0,0 +1,145 @@
+use std::collections::HashSet;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use api::{self, ApiMessage};
+use broadcast::Buffer;
+use config::{Config, Output, RandomSource, StreamConfig};
+use errlog::ErrLog;
+use error::{Error, Result};
+use events::Events;
+use library::{Library, TagFilter};
+use poll;
+use queue::Queue;
+use radio;
+
+/// Puts themed channels on air at runtime: a queue rotating through the library tracks matching a
+/// filter, played on copies of the default queue's mounts named <channel>-<mount>.
+pub struct Channels {
+    cfg: Config,
+    library: Library,
+    events: Events,
+    errors: ErrLog,
+    listeners: api::Listeners,
+    // Held while a channel starts, so that every name and mount id is handed out once
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    /// Names of the configured queues and the channels started so far
+    names: HashSet<String>,
+    /// Id of the next mount, following those of the configured streams
+    next_mount: usize,
+    btx: poll::Sender<Buffer>,
+    mounts: poll::Sender<StreamConfig>,
+}
+
+impl Channels {
+    pub fn new(cfg: &Config,
+               library: Library,
+               events: Events,
+               errors: ErrLog,
+               listeners: api::Listeners,
+               btx: poll::Sender<Buffer>,
+               mounts: poll::Sender<StreamConfig>)
+               -> Channels {
+        Channels {
+            cfg: cfg.clone(),
+            library,
+            events,
+            errors,
+            listeners,
+            inner: Mutex::new(Inner {
+                names: cfg.queues.keys().cloned().collect(),
+                next_mount: cfg.streams.len(),
+                btx,
+                mounts,
+            }),
+        }
+    }
+
+    /// Starts a channel playing the tracks matching the filter, returning its queue along with
+    /// the channel to its radio loop
+    pub fn start(&self, name: &str, filter: Vec<TagFilter>) -> Result<(Arc<Mutex<Queue>>, mpsc::Sender<ApiMessage>)> {
+        if self.cfg.library.is_none() {
+            return Err(Error::BadRequest(format!("channels need a [library] to pick tracks from")));
+        }
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(Error::BadRequest(format!("channel names may only contain letters, digits, - and _")));
+        }
+        let matching = self.library.count(&filter);
+        if matching == 0 {
+            return Err(Error::BadRequest(format!("no tracks in the library match the filter")));
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        if inner.names.contains(name) {
+            return Err(Error::BadRequest(format!("there already is a queue named {}", name)));
+        }
+        let btx = inner.btx.try_clone().map_err(Error::Socket)?;
+        let cfg = derive(&self.cfg, name, filter, inner.next_mount);
+        let queue = Queue::new(cfg.clone(), self.events.clone(), self.errors.clone(), self.library.clone())?;
+        // The broadcaster knows the mounts by the time the radio loop sends anything for them
+        for s in cfg.streams.iter() {
+            if inner.mounts.send(s.clone()).is_err() {
+                return Err(Error::Unavailable);
+            }
+        }
+        inner.next_mount += cfg.streams.len();
+        inner.names.insert(name.to_owned());
+
+        info!("Starting channel {} with {} matching tracks", name, matching);
+        let queue = Arc::new(Mutex::new(queue));
+        let (tx, rx) = mpsc::channel();
+        let (q, listeners, errors) = (queue.clone(), self.listeners.clone(), self.errors.clone());
+        thread::spawn(move || radio::start_streams(cfg, q, rx, btx, listeners, errors));
+        Ok((queue, tx))
+    }
+}
+
+/// The config of a channel: the default queue picking from the library instead, on copies of its
+/// mounts numbered from first_id
+fn derive(cfg: &Config, name: &str, filter: Vec<TagFilter>, first_id: usize) -> Config {
+    let mut cfg = cfg.for_queue(None);
+    cfg.queue.random = RandomSource::Library(filter);
+    for (i, s) in cfg.streams.iter_mut().enumerate() {
+        s.id = first_id + i;
+        s.mount = format!("{}-{}", name, s.mount);
+        s.queue = Some(name.to_owned());
+        // The outputs of the original mounts can't be shared, and only the configured mounts are
+        // recorded for timeshifting
+        s.output = Output::Listeners;
+        s.timeshift = None;
+    }
+    cfg
+}
+
+#[test]
+fn test_derive() {
+    let fallback = ::std::env::temp_dir().join("kawa-test-channel.ogg");
+    ::std::fs::write(&fallback, b"").unwrap();
+    let cfg = ::config::parse_config(&format!("[radio]\nport = 8001\nname = \"test\"\n[api]\nport = 4040\n\
+                                               [queue]\nrandom_song_api = \"http://localhost/random\"\n\
+                                               np = \"http://localhost/np\"\nfallback = {:?}\n\
+                                               [queues.requests]\nrandom_song_api = \"http://localhost/requests\"\n\
+                                               np = \"http://localhost/np\"\nfallback = {:?}\n\
+                                               [[streams]]\nmount = \"a.ogg\"\ncontainer = \"ogg\"\n\
+                                               output = \"null\"\n\
+                                               [[streams]]\nmount = \"b.ogg\"\ncontainer = \"ogg\"\n\
+                                               queue = \"requests\"\n\
+                                               [[streams]]\nmount = \"c.mp3\"\ncontainer = \"mp3\"\n",
+                                              fallback.to_str().unwrap(), fallback.to_str().unwrap())).unwrap();
+    let filter = vec![TagFilter::parse("genre=*chill*").unwrap()];
+    let derived = derive(&cfg, "chill", filter, 3);
+    let mounts: Vec<_> = derived.streams.iter().map(|s| (s.id, &*s.mount)).collect();
+    assert_eq!(mounts, vec![(3, "chill-a.ogg"), (4, "chill-c.mp3")]);
+    assert!(derived.streams.iter().all(|s| s.queue.as_ref().map(|q| &**q) == Some("chill")));
+    match derived.streams[0].output {
+        Output::Listeners => { }
+        _ => panic!("channel mounts must be served by kawa"),
+    }
+    match derived.queue.random {
+        RandomSource::Library(ref f) => assert_eq!(f[0].pattern, "*chill*"),
+        _ => panic!("channels must pick from the library"),
+    }
+}
@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use error::{Category, Error};
+use util;
+
+// Seconds during which repeats of an error are only counted, not logged
+const SUMMARY_INTERVAL: u64 = 60;
+
+/// Occurrences of one kind of error in one place
+#[derive(Clone, Serialize)]
+pub struct Summary {
+    /// What failed, e.g. "next entry" or "mount 0 output"
+    pub context: String,
+    pub code: &'static str,
+    pub category: Category,
+    /// Occurrences since startup
+    pub count: u64,
+    pub first_seen: u64,
+    pub last_seen: u64,
+    pub last_message: String,
+    // Occurrences which weren't logged yet
+    #[serde(skip_serializing)]
+    suppressed: u64,
+    #[serde(skip_serializing)]
+    last_logged: Option<time::Instant>,
+}
+
+/// Collects the errors reported from all over kawa. Repeats of the same error in the same context
+/// are collapsed, they're logged as a summary with their count at most once per interval.
+#[derive(Clone)]
+pub struct ErrLog {
+    summaries: Arc<Mutex<HashMap<(String, &'static str), Summary>>>,
+}
+
+impl ErrLog {
+    pub fn new() -> ErrLog {
+        ErrLog { summaries: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Logs an error, unless it was logged recently
+    pub fn report(&self, context: &str, e: &Error) {
+        let mut summaries = self.summaries.lock().unwrap();
+        let now = util::now();
+        let s = summaries.entry((context.to_owned(), e.code())).or_insert_with(|| Summary {
+            context: context.to_owned(),
+            code: e.code(),
+            category: e.category(),
+            count: 0,
+            first_seen: now,
+            last_seen: now,
+            last_message: String::new(),
+            suppressed: 0,
+            last_logged: None,
+        });
+        s.count += 1;
+        s.last_seen = now;
+        s.last_message = e.to_string();
+        match s.last_logged {
+            Some(t) if t.elapsed() < time::Duration::from_secs(SUMMARY_INTERVAL) => {
+                debug!("{} failed again ({}): {}", context, e.code(), e);
+                s.suppressed += 1;
+            }
+            _ => {
+                log(s.category, &format!("{} failed ({}): {}", context, e.code(), e));
+                s.last_logged = Some(time::Instant::now());
+            }
+        }
+    }
+
+    /// Logs summaries of the errors which were collapsed during the last interval. Should be
+    /// called periodically.
+    pub fn flush(&self) {
+        let mut summaries = self.summaries.lock().unwrap();
+        for s in summaries.values_mut() {
+            let due = s.last_logged.map(|t| t.elapsed() >= time::Duration::from_secs(SUMMARY_INTERVAL)).unwrap_or(true);
+            if s.suppressed > 0 && due {
+                log(s.category, &format!("{} failed {} more times ({}), last: {}",
+                                         s.context, s.suppressed, s.code, s.last_message));
+                s.suppressed = 0;
+                s.last_logged = Some(time::Instant::now());
+            }
+        }
+    }
+
+    /// Returns all errors seen since startup, most recent first
+    pub fn current(&self) -> Vec<Summary> {
+        let mut all: Vec<_> = self.summaries.lock().unwrap().values().cloned().collect();
+        all.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+        all
+    }
+}
+
+// Errors which need someone to fix the setup are more important than ones which may go away on
+// their own
+fn log(category: Category, msg: &str) {
+    match category {
+        Category::Config | Category::Transcode => error!("{}", msg),
+        Category::Network | Category::Api => warn!("{}", msg),
+    }
+}
@@ -0,0 +1,115 @@
+use std::{error, fmt, io};
+
+use kaeru;
+use reqwest;
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The config file is malformed or references something unusable
+    Config(String),
+    /// A file (queue entry, fallback, ...) could not be opened
+    FileMissing(String, io::Error),
+    /// The container or codec of an input or output is not supported
+    Unsupported(String),
+    /// ffmpeg failed while setting up or running a transcode
+    Transcode(kaeru::Error),
+    /// The announcement TTS command failed
+    Announce(String),
+    /// An HTTP request to an external service failed
+    Http(reqwest::Error),
+    /// An external service responded with something we couldn't use
+    BadResponse(String),
+    /// A socket could not be set up
+    Socket(io::Error),
+    /// An API request was malformed
+    BadRequest(String),
+    /// The track may not be played right now, because of the blocklist
+    Blocked(String),
+    /// The radio is not accepting API messages anymore
+    Unavailable,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Category {
+    Config,
+    Transcode,
+    Network,
+    Api,
+}
+
+impl Error {
+    pub fn category(&self) -> Category {
+        match *self {
+            Error::Config(_) => Category::Config,
+            Error::FileMissing(..)
+            | Error::Unsupported(_)
+            | Error::Transcode(_)
+            | Error::Announce(_) => Category::Transcode,
+            Error::Http(_)
+            | Error::BadResponse(_)
+            | Error::Socket(_) => Category::Network,
+            Error::BadRequest(_)
+            | Error::Blocked(_)
+            | Error::Unavailable => Category::Api,
+        }
+    }
+
+    /// A short, stable identifier for the kind of error, suitable for matching on by API clients
+    pub fn code(&self) -> &'static str {
+        match *self {
+            Error::Config(_) => "config",
+            Error::FileMissing(..) => "file_missing",
+            Error::Unsupported(_) => "unsupported",
+            Error::Transcode(_) => "transcode",
+            Error::Announce(_) => "announce",
+            Error::Http(_) => "http",
+            Error::BadResponse(_) => "bad_response",
+            Error::Socket(_) => "socket",
+            Error::BadRequest(_) => "bad_request",
+            Error::Blocked(_) => "blocked",
+            Error::Unavailable => "unavailable",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Config(ref s) => write!(f, "invalid config: {}", s),
+            Error::FileMissing(ref p, ref e) => write!(f, "could not open {}: {}", p, e),
+            Error::Unsupported(ref s) => write!(f, "unsupported: {}", s),
+            Error::Transcode(ref e) => write!(f, "transcode failed: {}", e),
+            Error::Announce(ref s) => write!(f, "announcement failed: {}", s),
+            Error::Http(ref e) => write!(f, "http request failed: {}", e),
+            Error::BadResponse(ref s) => write!(f, "bad response: {}", s),
+            Error::Socket(ref e) => write!(f, "socket error: {}", e),
+            Error::BadRequest(ref s) => write!(f, "{}", s),
+            Error::Blocked(ref s) => write!(f, "blocked: {}", s),
+            Error::Unavailable => write!(f, "radio is unavailable"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        self.code()
+    }
+}
+
+impl From<kaeru::Error> for Error {
+    fn from(e: kaeru::Error) -> Error {
+        if let kaeru::ErrorKind::Unsupported(ref s) = *e.kind() {
+            return Error::Unsupported(s.clone());
+        }
+        Error::Transcode(e)
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Error {
+        Error::Http(e)
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, mpsc};
+use std::{thread, time};
+
+use reqwest;
+use serde_json as serde;
+use serde_json::Value as JSON;
+
+use api;
+use config::{self, Config};
+use util;
+
+// Number of events kept around for GET /events
+const RECENT_LEN: usize = 256;
+// Seconds between checks of the listener count against the thresholds
+const LISTENER_INTERVAL: u64 = 1;
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// A queue entry didn't air before its expiry and was dropped
+    Expired { entry: JSON },
+    /// Summary of how a track's transcode went, sent once it ended
+    TrackReport {
+        entry: JSON,
+        /// Seconds of audio decoded, None for the fallback which isn't transcoded
+        duration: Option<f64>,
+        /// Seconds of audio transcoded per second spent transcoding, not counting the time spent
+        /// waiting for the playout to catch up
+        realtime_factor: Option<f64>,
+        decode_errors: usize,
+        /// Change in loudness by [normalize] in dB, None if it's off or the track was silent
+        gain: Option<f64>,
+        outputs: Vec<OutputReport>,
+        fallback: bool,
+        /// The track was skipped or replaced before its transcode finished
+        cancelled: bool,
+        error: Option<String>,
+    },
+    /// The total number of listeners rose to or dropped below a configured threshold
+    ListenerThreshold {
+        threshold: usize,
+        listeners: usize,
+        rising: bool,
+    },
+    /// A listener connected to a mount served by kawa itself
+    ListenerConnected {
+        id: usize,
+        mount: String,
+        user_agent: Option<String>,
+    },
+    /// A listener disconnected, after listening for `duration` seconds
+    ListenerDisconnected {
+        id: usize,
+        mount: String,
+        duration: u64,
+        bytes_sent: u64,
+    },
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct OutputReport {
+    pub mount: String,
+    pub bytes: usize,
+}
+
+/// Records events and delivers them to the configured webhooks.
+#[derive(Clone)]
+pub struct Events {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    recent: VecDeque<JSON>,
+    last_id: u64,
+    hooks: Option<mpsc::Sender<JSON>>,
+}
+
+impl Events {
+    pub fn new(cfg: &Config) -> Events {
+        let hooks = if cfg.events.webhooks.is_empty() {
+            None
+        } else {
+            let (tx, rx) = mpsc::channel();
+            let urls = cfg.events.webhooks.clone();
+            thread::spawn(move || deliver(rx, urls));
+            Some(tx)
+        };
+        Events {
+            inner: Arc::new(Mutex::new(Inner { recent: VecDeque::with_capacity(RECENT_LEN), last_id: 0, hooks })),
+        }
+    }
+
+    pub fn emit(&self, event: Event) {
+        debug!("Emitting event {:?}", event);
+        let mut inner = self.inner.lock().unwrap();
+        inner.last_id += 1;
+        let mut record = serde::to_value(&event).unwrap();
+        if let JSON::Object(ref mut o) = record {
+            o.insert("id".to_owned(), JSON::from(inner.last_id));
+            o.insert("time".to_owned(), JSON::from(util::now()));
+        }
+        if let Some(ref hooks) = inner.hooks {
+            hooks.send(record.clone()).ok();
+        }
+        inner.recent.push_back(record);
+        while inner.recent.len() > RECENT_LEN {
+            inner.recent.pop_front();
+        }
+    }
+
+    /// Returns the recent events with an id greater than since, oldest first
+    pub fn since(&self, since: u64) -> Vec<JSON> {
+        self.inner.lock().unwrap().recent.iter()
+            .filter(|e| e.get("id").and_then(|i| i.as_u64()).unwrap_or(0) > since)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Which side of the listener thresholds the count is on. A threshold is risen to as soon as the
+/// count reaches it, but only dropped below once the count falls a margin short of it, so that a
+/// count hovering around it doesn't emit events back and forth.
+struct Thresholds {
+    /// Thresholds along with whether the count is at or above them
+    thresholds: Vec<(usize, bool)>,
+    /// Percentage of a threshold the count must fall short of it by
+    margin: usize,
+}
+
+impl Thresholds {
+    fn new(thresholds: &[usize], margin: usize) -> Thresholds {
+        Thresholds { thresholds: thresholds.iter().map(|&t| (t, false)).collect(), margin }
+    }
+
+    /// Returns the events for the thresholds crossed by the new count
+    fn update(&mut self, count: usize) -> Vec<Event> {
+        let margin = self.margin;
+        let mut crossed = Vec::new();
+        for &mut (t, ref mut above) in self.thresholds.iter_mut() {
+            let rising = if !*above && count >= t {
+                true
+            } else if *above && count < t - t * margin / 100 {
+                false
+            } else {
+                continue;
+            };
+            *above = rising;
+            crossed.push(Event::ListenerThreshold { threshold: t, listeners: count, rising });
+        }
+        crossed
+    }
+}
+
+/// Emits an event whenever the number of listeners crosses one of the configured thresholds
+pub fn watch_listeners(cfg: &Config, listeners: api::Listeners, events: Events) {
+    if cfg.events.listener_thresholds.is_empty() {
+        return;
+    }
+    let mut thresholds = Thresholds::new(&cfg.events.listener_thresholds, cfg.events.listener_margin);
+    thread::spawn(move || loop {
+        thread::sleep(time::Duration::from_secs(LISTENER_INTERVAL));
+        let count = listeners.lock().unwrap().len();
+        for e in thresholds.update(count) {
+            events.emit(e);
+        }
+    });
+}
+
+fn deliver(rx: mpsc::Receiver<JSON>, urls: Vec<String>) {
+    let client = match reqwest::Client::new() {
+        Ok(c) => c,
+        Err(e) => {
+            warn!("Failed to create http client, events won't be delivered: {}", e);
+            return;
+        }
+    };
+    for event in rx {
+        for url in urls.iter() {
+            if let Err(e) = post(&client, url, &event) {
+                warn!("Failed to deliver event to {}: {}", config::redact_webhook(url), e);
+            }
+        }
+    }
+}
+
+fn post(client: &reqwest::Client, url: &str, event: &JSON) -> reqwest::Result<()> {
+    client.post(url)?
+        .json(event)?
+        .send()?;
+    Ok(())
+}
+
+#[test]
+fn test_thresholds() {
+    let mut thresholds = Thresholds::new(&[10, 100], 20);
+    let mut crossings = |count| -> Vec<(usize, bool)> {
+        thresholds.update(count).into_iter()
+            .map(|e| match e {
+                Event::ListenerThreshold { threshold, rising, .. } => (threshold, rising),
+                _ => unreachable!(),
+            })
+            .collect()
+    };
+    assert_eq!(crossings(9), vec![]);
+    assert_eq!(crossings(10), vec![(10, true)]);
+    // Hovering around the threshold stays quiet until the count drops below 8
+    assert_eq!(crossings(9), vec![]);
+    assert_eq!(crossings(10), vec![]);
+    assert_eq!(crossings(8), vec![]);
+    assert_eq!(crossings(7), vec![(10, false)]);
+    assert_eq!(crossings(9), vec![]);
+    assert_eq!(crossings(150), vec![(10, true), (100, true)]);
+    assert_eq!(crossings(0), vec![(10, false), (100, false)]);
+}
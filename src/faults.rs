@@ -0,0 +1,100 @@
+//! Faults which can be injected through POST /debug/faults, to check that kawa recovers from them.
+//! Injection is only possible in builds with the `faults` feature; in other builds the hooks never
+//! fire.
+
+#[cfg(feature = "faults")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "faults")]
+use std::{thread, time};
+
+use error::Result;
+#[cfg(not(feature = "faults"))]
+use error::Error;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "faults"), allow(dead_code))]
+pub struct Faults {
+    /// Drops the connections of all icecast outputs
+    #[serde(default)]
+    pub drop_icecast: bool,
+    /// Stalls the next transcoder to produce output for this many seconds
+    #[serde(default)]
+    pub stall_transcode: u64,
+    /// Replaces the responses of this many requests to the random source with garbage
+    #[serde(default)]
+    pub garbage_random: usize,
+}
+
+// Incremented by every injected drop, each icecast output drops its connection once it sees a
+// new value
+#[cfg(feature = "faults")]
+static ICECAST_DROPS: AtomicUsize = AtomicUsize::new(0);
+#[cfg(feature = "faults")]
+static STALL_SECS: AtomicUsize = AtomicUsize::new(0);
+#[cfg(feature = "faults")]
+static GARBAGE_RANDOM: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(feature = "faults")]
+pub fn inject(f: &Faults) -> Result<()> {
+    warn!("Injecting faults: {:?}", f);
+    if f.drop_icecast {
+        ICECAST_DROPS.fetch_add(1, Ordering::SeqCst);
+    }
+    if f.stall_transcode > 0 {
+        STALL_SECS.store(f.stall_transcode as usize, Ordering::SeqCst);
+    }
+    if f.garbage_random > 0 {
+        GARBAGE_RANDOM.store(f.garbage_random, Ordering::SeqCst);
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "faults"))]
+pub fn inject(_f: &Faults) -> Result<()> {
+    Err(Error::BadRequest(format!("kawa was built without the faults feature")))
+}
+
+/// Number of icecast connection drops injected so far
+#[cfg(feature = "faults")]
+pub fn icecast_drops() -> usize {
+    ICECAST_DROPS.load(Ordering::SeqCst)
+}
+
+#[cfg(not(feature = "faults"))]
+pub fn icecast_drops() -> usize {
+    0
+}
+
+/// Blocks the calling transcoder if a stall was injected
+#[cfg(feature = "faults")]
+pub fn stall_transcode() {
+    let secs = STALL_SECS.swap(0, Ordering::SeqCst);
+    if secs > 0 {
+        warn!("Injected fault: stalling transcoder for {}s", secs);
+        thread::sleep(time::Duration::from_secs(secs as u64));
+    }
+}
+
+#[cfg(not(feature = "faults"))]
+pub fn stall_transcode() {}
+
+/// Whether the current response of the random source should be replaced with garbage
+#[cfg(feature = "faults")]
+pub fn garbage_random() -> bool {
+    loop {
+        let n = GARBAGE_RANDOM.load(Ordering::SeqCst);
+        if n == 0 {
+            return false;
+        }
+        if GARBAGE_RANDOM.compare_exchange(n, n - 1, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            warn!("Injected fault: garbage from the random source");
+            return true;
+        }
+    }
+}
+
+#[cfg(not(feature = "faults"))]
+pub fn garbage_random() -> bool {
+    false
+}
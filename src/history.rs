@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json as serde;
+use serde_json::Value as JSON;
+
+use error::{Error, Result};
+use queue::QueueEntry;
+use util;
+
+/// A single play of a track, as recorded in the history log
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Play {
+    /// Unix timestamp of when the track started playing
+    pub time: u64,
+    pub id: u64,
+    pub path: String,
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    pub album: Option<String>,
+    pub isrc: Option<String>,
+    pub label: Option<String>,
+    /// Listeners connected across all mounts when the track started
+    pub listeners: usize,
+}
+
+impl Play {
+    pub fn new(entry: &QueueEntry, listeners: usize) -> Play {
+        let field = |name: &str| match entry.data.get(name) {
+            Some(&JSON::String(ref s)) => Some(s.clone()),
+            _ => None,
+        };
+        Play {
+            time: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            id: entry.id,
+            path: entry.path.clone(),
+            artist: field("artist"),
+            title: field("title"),
+            album: field("album"),
+            isrc: field("isrc"),
+            label: field("label"),
+            listeners,
+        }
+    }
+}
+
+/// Appends a play to the history log at path, one json object per line
+pub fn record(path: &str, play: &Play) -> io::Result<()> {
+    let mut f = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(f, "{}", serde::to_string(play).unwrap())
+}
+
+/// Loads all plays which started in [from, to) from the history log at path
+pub fn load(path: &str, from: u64, to: u64) -> Result<Vec<Play>> {
+    let f = File::open(path).map_err(|e| Error::FileMissing(path.to_owned(), e))?;
+    let mut plays = Vec::new();
+    for line in BufReader::new(f).lines() {
+        let line = line.map_err(|e| Error::FileMissing(path.to_owned(), e))?;
+        match serde::from_str::<Play>(&line) {
+            Ok(p) => if p.time >= from && p.time < to {
+                plays.push(p);
+            },
+            Err(e) => warn!("Skipping malformed history line: {}", e),
+        }
+    }
+    Ok(plays)
+}
+
+/// Renders plays as CSV, one row per play
+pub fn csv(plays: &[Play]) -> String {
+    let mut out = String::from("time,artist,title,album,isrc,label,listeners,path\r\n");
+    for p in plays {
+        let row = [
+            util::format_time(p.time),
+            csv_field(&p.artist),
+            csv_field(&p.title),
+            csv_field(&p.album),
+            csv_field(&p.isrc),
+            csv_field(&p.label),
+            p.listeners.to_string(),
+            csv_field(&Some(p.path.clone())),
+        ];
+        out += &row.join(",");
+        out += "\r\n";
+    }
+    out
+}
+
+/// Renders plays in SoundExchange's report of use format: tab separated, one row per sound
+/// recording, with the listeners of all its plays summed up as the actual total performances.
+pub fn soundexchange(service: &str, plays: &[Play]) -> String {
+    let mut rows: Vec<(Vec<String>, usize)> = Vec::new();
+    let mut idx = HashMap::new();
+    for p in plays {
+        let key = vec![
+            p.artist.clone().unwrap_or_default(),
+            p.title.clone().unwrap_or_else(|| p.path.clone()),
+            p.isrc.clone().unwrap_or_default(),
+            p.album.clone().unwrap_or_default(),
+            p.label.clone().unwrap_or_default(),
+        ];
+        let i = *idx.entry(key.clone()).or_insert_with(|| {
+            rows.push((key, 0));
+            rows.len() - 1
+        });
+        rows[i].1 += p.listeners;
+    }
+
+    let mut out = String::from("NAME_OF_SERVICE\tFEATURED_ARTIST\tSOUND_RECORDING_TITLE\tISRC\tALBUM_TITLE\
+                                \tMARKETING_LABEL\tACTUAL_TOTAL_PERFORMANCES\r\n");
+    for (fields, performances) in rows {
+        let mut row = vec![tsv_field(service)];
+        row.extend(fields.iter().map(|f| tsv_field(f)));
+        row.push(performances.to_string());
+        out += &row.join("\t");
+        out += "\r\n";
+    }
+    out
+}
+
+fn csv_field(f: &Option<String>) -> String {
+    match *f {
+        Some(ref s) if s.contains(|c: char| c == ',' || c == '"' || c == '\n' || c == '\r') => {
+            format!("\"{}\"", s.replace('"', "\"\""))
+        }
+        Some(ref s) => s.clone(),
+        None => String::new(),
+    }
+}
+
+fn tsv_field(f: &str) -> String {
+    f.replace(|c: char| c == '\t' || c == '\n' || c == '\r', " ")
+}
@@ -0,0 +1,226 @@
+use std::{cmp, io};
+
+use broadcast::Chunker;
+
+/// Bytes of audio between two metadata blocks
+pub const METAINT: usize = 16000;
+// The length of a block is sent as a single byte counting 16 byte units
+const MAX_BLOCK_LEN: usize = 255 * 16;
+
+/// Interleaves ICY metadata blocks into a client's audio stream. Blocks carry the current title
+/// and, once, any cue injected since the last block.
+pub struct Icy {
+    title: String,
+    cue: Option<String>,
+    changed: bool,
+    /// Audio bytes left until the next metadata block
+    until: usize,
+    /// Metadata block being written, with the amount of it already written
+    pending: Option<(Vec<u8>, usize)>,
+}
+
+impl Icy {
+    pub fn new(title: &str) -> Icy {
+        Icy {
+            title: title.to_owned(),
+            cue: None,
+            changed: true,
+            until: METAINT,
+            pending: None,
+        }
+    }
+
+    pub fn set_title(&mut self, title: &str) {
+        self.title = title.to_owned();
+        self.changed = true;
+    }
+
+    pub fn cue(&mut self, name: &str) {
+        self.cue = Some(name.to_owned());
+        self.changed = true;
+    }
+
+    /// Writes data through the chunker, stopping short at the next metadata block. Returns the
+    /// amount of audio written, like `Chunker::write`.
+    pub fn write<T: io::Write>(&mut self, chunker: &mut Chunker, conn: &mut T, data: &[u8]) -> io::Result<Option<usize>> {
+        if let Some((block, off)) = self.pending.take() {
+            match chunker.write(conn, &block[off..]) {
+                Ok(Some(n)) if off + n == block.len() => self.until = METAINT,
+                Ok(Some(n)) => {
+                    self.pending = Some((block, off + n));
+                    return Ok(None);
+                }
+                Ok(None) => {
+                    self.pending = Some((block, off));
+                    return Ok(None);
+                }
+                Err(e) => {
+                    self.pending = Some((block, off));
+                    return Err(e);
+                }
+            }
+        }
+        let len = cmp::min(self.until, data.len());
+        let res = chunker.write(conn, &data[..len])?;
+        if let Some(n) = res {
+            self.until -= n;
+            if self.until == 0 {
+                self.pending = Some((self.block(), 0));
+            }
+        }
+        Ok(res)
+    }
+
+    fn block(&mut self) -> Vec<u8> {
+        // An empty block means the metadata didn't change
+        if !self.changed {
+            return vec![0];
+        }
+        self.changed = false;
+        // The values are cut short rather than the block, which would lose the closing quotes.
+        // Cues are short and matter more than the end of a title.
+        let cue = self.cue.take().map(|c| escape(&c));
+        let overhead = "StreamTitle='';".len() + cue.as_ref().map(|_| "KawaCue='';".len()).unwrap_or(0);
+        let cue = cue.as_ref().map(|c| truncate(c, MAX_BLOCK_LEN - overhead));
+        let title = escape(&self.title);
+        let title = truncate(&title, MAX_BLOCK_LEN - overhead - cue.map(|c| c.len()).unwrap_or(0));
+        let mut meta = format!("StreamTitle='{}';", title);
+        if let Some(cue) = cue {
+            meta += &format!("KawaCue='{}';", cue);
+        }
+        let mut meta = meta.into_bytes();
+        let units = (meta.len() + 15) / 16;
+        meta.resize(units * 16, 0);
+        meta.insert(0, units as u8);
+        meta
+    }
+}
+
+// Quotes can't be escaped in ICY metadata, so they're replaced
+fn escape(s: &str) -> String {
+    s.replace('\'', "\u{2019}")
+}
+
+/// Returns at most the first `max` bytes of s, ending on a char boundary
+fn truncate(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Connection accepting at most `cap` bytes per write
+    struct Conn {
+        out: Vec<u8>,
+        cap: usize,
+    }
+
+    impl io::Write for Conn {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.cap == 0 {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "full"));
+            }
+            let n = cmp::min(self.cap, buf.len());
+            self.out.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn write_all(icy: &mut Icy, chunker: &mut Chunker, conn: &mut Conn, mut data: &[u8]) {
+        while !data.is_empty() {
+            if let Some(n) = icy.write(chunker, conn, data).unwrap() {
+                data = &data[n..];
+            }
+        }
+    }
+
+    /// Strips the chunked transfer encoding, the last chunk may be cut short
+    fn dechunk(mut data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        while !data.is_empty() {
+            let line = data.iter().position(|b| *b == b'\r').unwrap();
+            let len = usize::from_str_radix(::std::str::from_utf8(&data[..line]).unwrap(), 16).unwrap();
+            data = &data[line + 2..];
+            let n = cmp::min(len, data.len());
+            out.extend_from_slice(&data[..n]);
+            data = &data[cmp::min(n + 2, data.len())..];
+        }
+        out
+    }
+
+    fn block(meta: &str) -> Vec<u8> {
+        let mut b = meta.as_bytes().to_vec();
+        let units = (b.len() + 15) / 16;
+        b.resize(units * 16, 0);
+        b.insert(0, units as u8);
+        b
+    }
+
+    #[test]
+    fn test_framing() {
+        let audio: Vec<u8> = (0..2 * METAINT + 100).map(|i| (i % 251) as u8).collect();
+        let mut icy = Icy::new("It's a title");
+        icy.cue("ad");
+        let mut chunker = Chunker::new();
+        let mut conn = Conn { out: Vec::new(), cap: usize::max_value() };
+        write_all(&mut icy, &mut chunker, &mut conn, &audio);
+
+        let mut expected = audio[..METAINT].to_vec();
+        expected.extend(block("StreamTitle='It\u{2019}s a title';KawaCue='ad';"));
+        expected.extend_from_slice(&audio[METAINT..2 * METAINT]);
+        // Unchanged metadata is sent as an empty block, and the cue only once
+        expected.push(0);
+        expected.extend_from_slice(&audio[2 * METAINT..]);
+        assert!(dechunk(&conn.out) == expected);
+    }
+
+    #[test]
+    fn test_long_title() {
+        // Multibyte chars, so the cut can't fall on a char boundary by accident
+        let mut icy = Icy::new(&"ü".repeat(MAX_BLOCK_LEN));
+        icy.cue("ad");
+        let b = icy.block();
+        assert_eq!(b.len(), 1 + MAX_BLOCK_LEN);
+        let meta = ::std::str::from_utf8(&b[1..]).unwrap().trim_end_matches('\0');
+        assert!(meta.starts_with("StreamTitle='ü"));
+        assert!(meta.ends_with("ü';KawaCue='ad';"));
+        assert!(meta.len() > MAX_BLOCK_LEN - 2);
+    }
+
+    #[test]
+    fn test_pending_block() {
+        let audio: Vec<u8> = (0..METAINT + 100).map(|i| (i % 251) as u8).collect();
+        let mut icy = Icy::new("one");
+        let mut chunker = Chunker::new();
+        let mut conn = Conn { out: Vec::new(), cap: usize::max_value() };
+        write_all(&mut icy, &mut chunker, &mut conn, &audio[..METAINT]);
+
+        // The connection only takes part of the metadata block, then blocks
+        conn.cap = 7;
+        assert_eq!(icy.write(&mut chunker, &mut conn, &audio[METAINT..]).unwrap(), None);
+        conn.cap = 0;
+        assert!(icy.write(&mut chunker, &mut conn, &audio[METAINT..]).is_err());
+        // A title change while the block is pending makes it into the next block, not this one
+        icy.set_title("two");
+        conn.cap = usize::max_value();
+        write_all(&mut icy, &mut chunker, &mut conn, &audio[METAINT..]);
+
+        let mut expected = audio[..METAINT].to_vec();
+        expected.extend(block("StreamTitle='one';"));
+        expected.extend_from_slice(&audio[METAINT..]);
+        assert!(dechunk(&conn.out) == expected);
+        assert!(icy.changed);
+    }
+}
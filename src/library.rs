@@ -0,0 +1,316 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::{thread, time};
+
+use kaeru;
+use toml;
+use serde_json::Map;
+use serde_json::Value as JSON;
+
+use blocklist::glob_match;
+use config::Config;
+use error::{Error, Result};
+use queue::NewQueueEntry;
+use util::{self, Rng};
+
+/// A rule a track's tag must match to be part of a derived channel
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TagFilter {
+    /// Tag to match, e.g. "genre", or "path"
+    pub field: String,
+    /// Pattern the tag is matched against, case insensitively. May use * as a wildcard.
+    pub pattern: String,
+}
+
+impl TagFilter {
+    /// Parses a filter of the form field=pattern
+    pub fn parse(s: &str) -> Option<TagFilter> {
+        let mut parts = s.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(f), Some(p)) if !f.is_empty() => Some(TagFilter { field: f.to_owned(), pattern: p.to_owned() }),
+            _ => None,
+        }
+    }
+}
+
+/// The tracks found in the [library] directory, as queue entries carrying their tags
+#[derive(Clone)]
+pub struct Library {
+    inner: Arc<Mutex<Inner>>,
+    /// Notified after every scan
+    scanned: Arc<Condvar>,
+}
+
+struct Inner {
+    tracks: Vec<NewQueueEntry>,
+    /// Incremented with every scan, so rotations know to pick up changes
+    generation: u64,
+}
+
+/// Plays every track matching a filter once, in random order, before starting over
+pub struct Rotation {
+    order: Vec<NewQueueEntry>,
+    /// Paths of the matching tracks as of the last scan, including those played already
+    known: HashSet<String>,
+    generation: u64,
+    rng: Rng,
+}
+
+impl Library {
+    pub fn new() -> Library {
+        Library {
+            inner: Arc::new(Mutex::new(Inner { tracks: Vec::new(), generation: 0 })),
+            scanned: Arc::new(Condvar::new()),
+        }
+    }
+
+    /// Scans the configured library in the background, and again every `rescan` minutes
+    pub fn start(cfg: &Config) -> Library {
+        let lib = Library::new();
+        let lcfg = match cfg.library {
+            Some(ref l) => l.clone(),
+            None => return lib,
+        };
+        let handle = lib.clone();
+        thread::spawn(move || loop {
+            let start = time::Instant::now();
+            let tracks = scan(&lcfg.dir);
+            info!("Found {} tracks in {} in {}s", tracks.len(), lcfg.dir, start.elapsed().as_secs());
+            {
+                let mut inner = handle.inner.lock().unwrap();
+                inner.tracks = tracks;
+                inner.generation += 1;
+            }
+            handle.scanned.notify_all();
+            thread::sleep(time::Duration::from_secs(lcfg.rescan * 60));
+        });
+        lib
+    }
+
+    /// Blocks until the library was scanned once. Must only be called if a library is configured.
+    pub fn wait_scanned(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        while inner.generation == 0 {
+            inner = self.scanned.wait(inner).unwrap();
+        }
+    }
+
+    /// Number of tracks matching all of the filters
+    pub fn count(&self, filter: &[TagFilter]) -> usize {
+        self.inner.lock().unwrap().tracks.iter().filter(|t| matches(t, filter)).count()
+    }
+
+    /// Returns the tracks matching all of the filters, along with the generation of the scan
+    fn matching(&self, filter: &[TagFilter]) -> (u64, Vec<NewQueueEntry>) {
+        let inner = self.inner.lock().unwrap();
+        let tracks = inner.tracks.iter()
+            .filter(|t| matches(t, filter))
+            .cloned()
+            .collect();
+        (inner.generation, tracks)
+    }
+}
+
+impl Rotation {
+    pub fn new() -> Rotation {
+        let seed = util::now() ^ (time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .map(|d| d.subsec_nanos() as u64)
+            .unwrap_or(0) << 32);
+        Rotation { order: Vec::new(), known: HashSet::new(), generation: 0, rng: Rng(seed | 1) }
+    }
+
+    /// Returns the next track of the rotation. A rescan of the library keeps the rotation going:
+    /// tracks which disappeared are dropped from it, and new ones are shuffled in.
+    pub fn next(&mut self, lib: &Library, filter: &[TagFilter]) -> Option<NewQueueEntry> {
+        let generation = lib.inner.lock().unwrap().generation;
+        if self.order.is_empty() {
+            let (generation, mut tracks) = lib.matching(filter);
+            // Fisher-Yates, popped from the back below
+            for i in (1..tracks.len()).rev() {
+                let j = (self.rng.next_u64() % (i as u64 + 1)) as usize;
+                tracks.swap(i, j);
+            }
+            self.known = tracks.iter().map(|t| t.path.clone()).collect();
+            self.order = tracks;
+            self.generation = generation;
+        } else if generation != self.generation {
+            let (generation, tracks) = lib.matching(filter);
+            let current: HashSet<&str> = tracks.iter().map(|t| &*t.path).collect();
+            self.order.retain(|t| current.contains(&*t.path));
+            let added: Vec<_> = tracks.iter().filter(|t| !self.known.contains(&t.path)).cloned().collect();
+            for t in added {
+                let i = (self.rng.next_u64() % (self.order.len() as u64 + 1)) as usize;
+                self.order.insert(i, t);
+            }
+            self.known = tracks.iter().map(|t| t.path.clone()).collect();
+            self.generation = generation;
+        }
+        self.order.pop()
+    }
+}
+
+/// Scans the library for the tracks a channel with the given filter would play, and prints the
+/// config for a queue playing them on copies of the default queue's mounts, prefixed with the
+/// channel's name.
+pub fn channel(cfg: &Config, name: &str, filter: &[TagFilter]) -> Result<()> {
+    let dir = match cfg.library {
+        Some(ref l) => &l.dir,
+        None => return Err(Error::Config(format!("channels need a [library] to pick tracks from"))),
+    };
+    let tracks = scan(dir);
+    let matching = tracks.iter().filter(|t| matches(t, filter)).count();
+    println!("{} of {} tracks in {} match", matching, tracks.len(), dir);
+    if matching == 0 {
+        return Ok(());
+    }
+
+    let mut queue = toml::value::Table::new();
+    for key in &["np", "fallback", "history", "cooldown"] {
+        if let Some(v) = cfg.raw.get("queue").and_then(|q| q.get(*key)) {
+            queue.insert(key.to_string(), v.clone());
+        }
+    }
+    let filter = filter.iter()
+        .map(|f| {
+            let mut t = toml::value::Table::new();
+            t.insert("field".to_owned(), toml::Value::String(f.field.clone()));
+            t.insert("pattern".to_owned(), toml::Value::String(f.pattern.clone()));
+            toml::Value::Table(t)
+        })
+        .collect();
+    queue.insert("filter".to_owned(), toml::Value::Array(filter));
+    let mut queues = toml::value::Table::new();
+    queues.insert(name.to_owned(), toml::Value::Table(queue));
+
+    let mut streams = Vec::new();
+    for s in cfg.raw.get("streams").and_then(|s| s.as_array()).map(|s| &s[..]).unwrap_or(&[]) {
+        let mut s = match s.as_table() {
+            Some(s) if !s.contains_key("queue") => s.clone(),
+            _ => continue,
+        };
+        let mount = format!("{}-{}", name, s.get("mount").and_then(|m| m.as_str()).unwrap_or(""));
+        s.insert("mount".to_owned(), toml::Value::String(mount));
+        s.insert("queue".to_owned(), toml::Value::String(name.to_owned()));
+        // The outputs of the original mounts can't be shared
+        s.remove("output");
+        streams.push(toml::Value::Table(s));
+    }
+
+    let mut out = toml::value::Table::new();
+    out.insert("queues".to_owned(), toml::Value::Table(queues));
+    out.insert("streams".to_owned(), toml::Value::Array(streams));
+    let out = toml::to_string(&toml::Value::Table(out))
+        .map_err(|e| Error::Config(format!("failed to write channel config: {}", e)))?;
+    println!("Add the following to the config to play them:\n\n{}", out);
+    Ok(())
+}
+
+/// Reads the tags of every file below dir which ffmpeg can open. Files which can't be read are
+/// skipped.
+pub fn scan(dir: &str) -> Vec<NewQueueEntry> {
+    let mut tracks = Vec::new();
+    let mut dirs = vec![Path::new(dir).to_path_buf()];
+    while let Some(d) = dirs.pop() {
+        let entries = match fs::read_dir(&d) {
+            Ok(e) => e,
+            Err(e) => {
+                warn!("Failed to read library directory {:?}: {}", d, e);
+                continue;
+            }
+        };
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if path.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+            let path = path.to_string_lossy().into_owned();
+            match track(&path) {
+                Some(t) => tracks.push(t),
+                None => debug!("Skipping {} in library, it can't be read", path),
+            }
+        }
+    }
+    tracks.sort_by(|a, b| a.path.cmp(&b.path));
+    tracks
+}
+
+fn track(path: &str) -> Option<NewQueueEntry> {
+    let ext = util::container_ext(path)?;
+    let f = fs::File::open(path).ok()?;
+    let metadata = kaeru::Input::new(f, ext).ok()?.metadata();
+    let mut data = Map::new();
+    let tags = vec![
+        ("title", metadata.title),
+        ("artist", metadata.artist),
+        ("album", metadata.album),
+        ("genre", metadata.genre),
+        ("date", metadata.date),
+    ];
+    for (k, v) in tags {
+        if let Some(v) = v {
+            data.insert(k.to_owned(), JSON::String(v));
+        }
+    }
+    data.insert("path".to_owned(), JSON::String(path.to_owned()));
+    Some(NewQueueEntry { data, path: path.to_owned(), ..Default::default() })
+}
+
+pub fn matches(track: &NewQueueEntry, filter: &[TagFilter]) -> bool {
+    filter.iter().all(|f| {
+        match track.data.get(&f.field).and_then(|v| v.as_str()) {
+            Some(v) => glob_match(&f.pattern.to_lowercase(), &v.to_lowercase()),
+            None => false,
+        }
+    })
+}
+
+#[test]
+fn test_matches() {
+    let mut data = Map::new();
+    data.insert("genre".to_owned(), JSON::String("Chillout".to_owned()));
+    data.insert("path".to_owned(), JSON::String("/music/a.flac".to_owned()));
+    let track = NewQueueEntry { data, path: "/music/a.flac".to_owned(), ..Default::default() };
+    let filter = |field: &str, pattern: &str| TagFilter { field: field.to_owned(), pattern: pattern.to_owned() };
+    assert!(matches(&track, &[filter("genre", "chill*")]));
+    assert!(matches(&track, &[filter("genre", "chill*"), filter("path", "/music/*")]));
+    assert!(!matches(&track, &[filter("genre", "chill*"), filter("artist", "*")]));
+    assert!(matches(&track, &[]));
+}
+
+#[test]
+fn test_rotation_rescan() {
+    let track = |p: &str| NewQueueEntry { path: p.to_owned(), ..Default::default() };
+    let lib = Library::new();
+    let scan = |paths: &[&str]| {
+        let mut inner = lib.inner.lock().unwrap();
+        inner.tracks = paths.iter().map(|p| track(p)).collect();
+        inner.generation += 1;
+    };
+    scan(&["a", "b", "c", "d"]);
+    let mut rotation = Rotation::new();
+    let first = rotation.next(&lib, &[]).unwrap().path;
+    let gone = if first == "a" { "b" } else { "a" };
+    let kept: Vec<_> = ["a", "b", "c", "d"].iter().cloned().filter(|&p| p != first && p != gone).collect();
+    let mut rescanned = kept.clone();
+    rescanned.push(&first);
+    rescanned.push("e");
+    scan(&rescanned);
+    // The rotation goes on without the played and the removed track, and with the new one
+    let mut rest: Vec<_> = (0..3).map(|_| rotation.next(&lib, &[]).unwrap().path).collect();
+    rest.sort();
+    let mut expected: Vec<_> = kept.iter().map(|p| p.to_string()).collect();
+    expected.push("e".to_owned());
+    expected.sort();
+    assert_eq!(rest, expected);
+    // Then a new one starts with all of them
+    let mut next: Vec<_> = (0..4).map(|_| rotation.next(&lib, &[]).unwrap().path).collect();
+    next.sort();
+    let mut all: Vec<_> = rescanned.iter().map(|p| p.to_string()).collect();
+    all.sort();
+    assert_eq!(next, all);
+}
@@ -13,9 +13,11 @@ extern crate reqwest;
 extern crate serde_derive;
 #[macro_use]
 extern crate rouille;
+#[cfg(unix)]
 extern crate amy;
 extern crate httparse;
 extern crate url;
+extern crate libc;
 
 extern crate kaeru;
 
@@ -27,8 +29,27 @@ mod util;
 mod tc_queue;
 mod prebuffer;
 mod broadcast;
+mod error;
+mod bench;
+mod soak;
+mod transcode;
+mod blocklist;
+mod standby;
+mod timeshift;
+mod history;
+mod announce;
+mod events;
+mod migrate;
+mod icy;
+mod output;
+mod errlog;
+mod poll;
+mod library;
+mod channel;
+mod probe;
+mod faults;
 
-use std::env;
+use std::{env, thread};
 use std::sync::{Arc, Mutex, mpsc};
 use std::io::{Read};
 use std::collections::HashMap;
@@ -46,34 +67,166 @@ fn main() {
     info!("Initializing ffmpeg");
     kaeru::init();
 
-    let path = env::args().nth(1).unwrap_or("config.toml".to_owned());
+    let mut args = env::args().skip(1);
+    match args.next() {
+        Some(ref cmd) if cmd == "bench" => {
+            let file = match args.next() {
+                Some(f) => f,
+                None => {
+                    error!("Usage: kawa bench <file> [config]");
+                    return;
+                }
+            };
+            let config = match load_config(args.next()) {
+                Some(c) => c,
+                None => return,
+            };
+            if let Err(e) = bench::run(&config, &file) {
+                error!("Benchmark failed: {}", e);
+            }
+        }
+        Some(ref cmd) if cmd == "soak" => {
+            let secs = match args.next().and_then(|s| s.parse().ok()) {
+                Some(s) => s,
+                None => {
+                    error!("Usage: kawa soak <seconds> [config]");
+                    return;
+                }
+            };
+            if let Some(config) = load_config(args.next()) {
+                soak::run(config, std::time::Duration::from_secs(secs));
+            }
+        }
+        Some(ref cmd) if cmd == "channel" => {
+            let name = match args.next() {
+                Some(n) => n,
+                None => {
+                    error!("Usage: kawa channel <name> <tag>=<pattern>... [config]");
+                    return;
+                }
+            };
+            let (filter, rest): (Vec<_>, Vec<_>) = args.partition(|a| a.contains('='));
+            let filter: Vec<_> = filter.iter().filter_map(|f| library::TagFilter::parse(f)).collect();
+            if let Some(config) = load_config(rest.into_iter().next()) {
+                if let Err(e) = library::channel(&config, &name, &filter) {
+                    error!("Failed to set up channel: {}", e);
+                }
+            }
+        }
+        Some(ref cmd) if cmd == "migrate-config" => {
+            let (old, new) = match (args.next(), args.next()) {
+                (Some(o), Some(n)) => (o, n),
+                _ => {
+                    error!("Usage: kawa migrate-config <old config> <new config>");
+                    return;
+                }
+            };
+            if let Err(e) = migrate::run(&old, &new) {
+                error!("Failed to migrate config: {}", e);
+            }
+        }
+        path => {
+            if let Some(config) = load_config(path) {
+                run(config);
+            }
+        }
+    }
+}
+
+fn load_config(path: Option<String>) -> Option<config::Config> {
+    let path = path.unwrap_or("config.toml".to_owned());
     let mut s = String::new();
     if let Ok(mut f) = std::fs::File::open(&path) {
         if f.read_to_string(&mut s).is_err() {
             error!("Config file could not be read!");
-            return;
+            return None;
         }
     } else {
         error!("A config file path must be passed as argv[1] or must exist as ./config.toml");
-        return;
+        return None;
     }
 
     info!("Initializing config");
-    let config = match config::parse_config(&s) {
-        Ok(c) => c,
+    match config::parse_config(&s) {
+        Ok(c) => Some(c),
         Err(e) => {
             error!("Failed to parse config: {}", e);
+            None
+        }
+    }
+}
+
+fn run(config: config::Config) {
+    info!("Starting");
+    let events = events::Events::new(&config);
+    let errors = errlog::ErrLog::new();
+    let library = library::Library::start(&config);
+    // The queues and their fallbacks are set up before waiting, so that a standby airs as soon as
+    // it takes over
+    let mut queue = match queue::Queue::idle(config.for_queue(None), events.clone(), errors.clone(), library.clone()) {
+        Ok(q) => q,
+        Err(e) => {
+            error!("Failed to initialize queue: {}", e);
             return;
         }
     };
+    let mut queues = Vec::new();
+    for name in config.queues.keys() {
+        let cfg = config.for_queue(Some(name));
+        match queue::Queue::idle(cfg.clone(), events.clone(), errors.clone(), library.clone()) {
+            Ok(q) => queues.push((name.clone(), cfg, q)),
+            Err(e) => {
+                error!("Failed to initialize queue {}: {}", name, e);
+                return;
+            }
+        }
+    }
+    let mirror = match config.standby {
+        Some(ref sb) => standby::wait_for_takeover(sb),
+        None => Vec::new(),
+    };
+    // Queues picking from the library would otherwise find it empty and fall back
+    let from_library = |q: &config::QueueConfig| match q.random {
+        config::RandomSource::Library(_) => true,
+        config::RandomSource::Api(_) => false,
+    };
+    if from_library(&config.queue) || config.queues.values().any(|q| from_library(q)) {
+        info!("Waiting for the first library scan");
+        library.wait_scanned();
+    }
 
-    info!("Starting");
-    let queue = Arc::new(Mutex::new(queue::Queue::new(config.clone())));
-    let listeners = Arc::new(Mutex::new(HashMap::new()));
+    queue.start(mirror);
+    let queue = Arc::new(Mutex::new(queue));
     let (tx, rx) = mpsc::channel();
-    let btx = broadcast::start(&config, listeners.clone());
-    api::start_api(config.api.clone(), queue.clone(), listeners, tx);
-    radio::start_streams(config.clone(), queue, rx, btx);
+    let mut api_queues = vec![(None, queue.clone(), tx)];
+    // Independent queues get a radio loop of their own
+    let mut loops = Vec::new();
+    for (name, cfg, mut q) in queues {
+        q.start(Vec::new());
+        let q = Arc::new(Mutex::new(q));
+        let (tx, rx) = mpsc::channel();
+        api_queues.push((Some(name), q.clone(), tx));
+        loops.push((cfg, q, rx));
+    }
+    let listeners = Arc::new(Mutex::new(HashMap::new()));
+    let (btx, mounts) = match broadcast::start(&config, listeners.clone(), events.clone()) {
+        Ok(b) => b,
+        Err(e) => {
+            error!("Failed to start broadcaster: {}", e);
+            return;
+        }
+    };
+    events::watch_listeners(&config, listeners.clone(), events.clone());
+    let channels = channel::Channels::new(&config, library, events.clone(), errors.clone(), listeners.clone(),
+                                          btx.try_clone().unwrap(), mounts);
+    api::start_api(config.clone(), api_queues, listeners.clone(), events, errors.clone(), channels);
+    for (cfg, q, rx) in loops {
+        let btx = btx.try_clone().unwrap();
+        let listeners = listeners.clone();
+        let errors = errors.clone();
+        thread::spawn(move || radio::start_streams(cfg, q, rx, btx, listeners, errors));
+    }
+    radio::start_streams(config.for_queue(None), queue, rx, btx, listeners, errors);
 }
 
 #[cfg(test)]
@@ -0,0 +1,251 @@
+//! The broadcaster's event loop runs on amy, which wraps epoll and kqueue. Windows has neither, so
+//! there a small stand-in with the same interface, built on WSAPoll, is used instead. Unlike
+//! amy's, its channels and timers can't wake a wait, so waits are cut short to a tick. The Windows
+//! build isn't covered by CI and hasn't been run.
+
+#[cfg(unix)]
+pub use amy::{Event, Poller, Receiver, Registrar, Sender};
+
+#[cfg(windows)]
+pub use self::fallback::{Event, Poller, Receiver, Registrar, Sender};
+
+#[cfg(windows)]
+mod fallback {
+    use std::collections::HashMap;
+    use std::os::windows::io::{AsRawSocket, RawSocket};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::{cmp, io, thread, time};
+
+    // Longest a wait blocks in milliseconds, as channels and timers are only checked in between
+    const TICK: usize = 5;
+
+    const POLLRDNORM: i16 = 0x0100;
+    const POLLWRNORM: i16 = 0x0010;
+    const POLLERR: i16 = 0x0001;
+    const POLLHUP: i16 = 0x0002;
+
+    #[repr(C)]
+    struct PollFd {
+        fd: usize,
+        events: i16,
+        revents: i16,
+    }
+
+    #[link(name = "ws2_32")]
+    extern "system" {
+        fn WSAPoll(fds: *mut PollFd, nfds: u32, timeout: i32) -> i32;
+    }
+
+    #[derive(Clone, Copy, Debug)]
+    pub enum Event {
+        Read,
+        Write,
+        Both,
+    }
+
+    pub struct Notification {
+        pub id: usize,
+        pub event: Event,
+    }
+
+    struct Socket {
+        id: usize,
+        event: Event,
+        /// Whether the socket was writable when last polled. Like amy, writability is only
+        /// reported when it changes, otherwise every listener would be reported on every wait.
+        writable: bool,
+    }
+
+    struct Timer {
+        id: usize,
+        interval: time::Duration,
+        last: time::Instant,
+    }
+
+    struct State {
+        next_id: usize,
+        sockets: HashMap<RawSocket, Socket>,
+        timers: Vec<Timer>,
+        /// Channels along with whether anything was sent since they were last reported
+        channels: Vec<(usize, Arc<AtomicBool>)>,
+    }
+
+    impl State {
+        fn id(&mut self) -> usize {
+            self.next_id += 1;
+            self.next_id
+        }
+
+        fn fds(&self, writable: bool) -> Vec<PollFd> {
+            self.sockets.iter()
+                .filter(|&(_, s)| s.writable == writable)
+                .map(|(&fd, s)| PollFd {
+                    fd: fd as usize,
+                    events: match s.event {
+                        Event::Read => POLLRDNORM,
+                        Event::Write => POLLWRNORM,
+                        Event::Both => POLLRDNORM | POLLWRNORM,
+                    },
+                    revents: 0,
+                })
+                .collect()
+        }
+
+        /// Records the results of a poll, returning the sockets which became ready
+        fn update(&mut self, fds: &[PollFd], ready: &mut Vec<Notification>) {
+            for fd in fds {
+                let s = match self.sockets.get_mut(&(fd.fd as RawSocket)) {
+                    Some(s) => s,
+                    None => continue,
+                };
+                let failed = fd.revents & (POLLERR | POLLHUP) != 0;
+                let readable = failed || fd.revents & POLLRDNORM != 0;
+                let writable = failed || fd.revents & POLLWRNORM != 0;
+                match s.event {
+                    Event::Read if readable => ready.push(Notification { id: s.id, event: Event::Read }),
+                    Event::Read => { }
+                    _ => {
+                        if (writable && !s.writable) || readable {
+                            ready.push(Notification { id: s.id, event: s.event });
+                        }
+                        s.writable = writable;
+                    }
+                }
+            }
+        }
+    }
+
+    fn poll(fds: &mut [PollFd], timeout_ms: usize) -> io::Result<()> {
+        if fds.is_empty() {
+            thread::sleep(time::Duration::from_millis(timeout_ms as u64));
+            return Ok(());
+        }
+        if unsafe { WSAPoll(fds.as_mut_ptr(), fds.len() as u32, timeout_ms as i32) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub struct Poller {
+        state: Arc<Mutex<State>>,
+    }
+
+    pub struct Registrar {
+        state: Arc<Mutex<State>>,
+    }
+
+    pub struct Sender<T> {
+        tx: mpsc::Sender<T>,
+        pending: Arc<AtomicBool>,
+    }
+
+    pub struct Receiver<T> {
+        rx: mpsc::Receiver<T>,
+        id: usize,
+    }
+
+    impl Poller {
+        pub fn new() -> io::Result<Poller> {
+            let state = State { next_id: 0, sockets: HashMap::new(), timers: Vec::new(), channels: Vec::new() };
+            Ok(Poller { state: Arc::new(Mutex::new(state)) })
+        }
+
+        pub fn get_registrar(&self) -> io::Result<Registrar> {
+            Ok(Registrar { state: self.state.clone() })
+        }
+
+        pub fn wait(&mut self, timeout_ms: usize) -> io::Result<Vec<Notification>> {
+            let mut ready = Vec::new();
+            // Sockets which were writable are checked first without waiting, so that they're
+            // waited on again once they stop being writable
+            let mut probe = self.state.lock().unwrap().fds(true);
+            poll(&mut probe, 0)?;
+            let (mut fds, busy) = {
+                let mut state = self.state.lock().unwrap();
+                state.update(&probe, &mut ready);
+                let busy = state.channels.iter().any(|&(_, ref p)| p.load(Ordering::SeqCst))
+                    || state.timers.iter().any(|t| t.last.elapsed() >= t.interval);
+                (state.fds(false), busy)
+            };
+            let timeout = if busy || !ready.is_empty() { 0 } else { cmp::min(timeout_ms, TICK) };
+            poll(&mut fds, timeout)?;
+
+            let mut state = self.state.lock().unwrap();
+            state.update(&fds, &mut ready);
+            for &(id, ref pending) in state.channels.iter() {
+                if pending.swap(false, Ordering::SeqCst) {
+                    ready.push(Notification { id, event: Event::Read });
+                }
+            }
+            for t in state.timers.iter_mut() {
+                if t.last.elapsed() >= t.interval {
+                    t.last = time::Instant::now();
+                    ready.push(Notification { id: t.id, event: Event::Read });
+                }
+            }
+            Ok(ready)
+        }
+    }
+
+    impl Registrar {
+        pub fn register<T: AsRawSocket>(&mut self, sock: &T, event: Event) -> io::Result<usize> {
+            let mut state = self.state.lock().unwrap();
+            let id = state.id();
+            state.sockets.insert(sock.as_raw_socket(), Socket { id, event, writable: false });
+            Ok(id)
+        }
+
+        pub fn reregister<T: AsRawSocket>(&mut self, id: usize, sock: &T, event: Event) -> io::Result<()> {
+            self.state.lock().unwrap().sockets.insert(sock.as_raw_socket(), Socket { id, event, writable: false });
+            Ok(())
+        }
+
+        pub fn deregister<T: AsRawSocket>(&mut self, sock: &T) -> io::Result<()> {
+            self.state.lock().unwrap().sockets.remove(&sock.as_raw_socket());
+            Ok(())
+        }
+
+        pub fn set_interval(&mut self, ms: usize) -> io::Result<usize> {
+            let mut state = self.state.lock().unwrap();
+            let id = state.id();
+            state.timers.push(Timer {
+                id,
+                interval: time::Duration::from_millis(ms as u64),
+                last: time::Instant::now(),
+            });
+            Ok(id)
+        }
+
+        pub fn channel<T>(&mut self) -> io::Result<(Sender<T>, Receiver<T>)> {
+            let mut state = self.state.lock().unwrap();
+            let id = state.id();
+            let pending = Arc::new(AtomicBool::new(false));
+            state.channels.push((id, pending.clone()));
+            let (tx, rx) = mpsc::channel();
+            Ok((Sender { tx, pending }, Receiver { rx, id }))
+        }
+    }
+
+    impl<T> Sender<T> {
+        pub fn send(&self, data: T) -> Result<(), mpsc::SendError<T>> {
+            self.tx.send(data)?;
+            self.pending.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+
+        pub fn try_clone(&self) -> io::Result<Sender<T>> {
+            Ok(Sender { tx: self.tx.clone(), pending: self.pending.clone() })
+        }
+    }
+
+    impl<T> Receiver<T> {
+        pub fn try_recv(&self) -> Result<T, mpsc::TryRecvError> {
+            self.rx.try_recv()
+        }
+
+        pub fn get_id(&self) -> usize {
+            self.id
+        }
+    }
+}
@@ -5,6 +5,8 @@ use tc_queue;
 pub struct PreBuffer {
     pub buffer: tc_queue::QR,
     pub metadata: Arc<Metadata>,
+    /// Whether the buffer is the announcement played before the track, rather than the track
+    pub announcement: bool,
 }
 
 impl PreBuffer {
@@ -12,6 +14,7 @@ impl PreBuffer {
         PreBuffer {
             buffer,
             metadata: md,
+            announcement: false,
         }
     }
 }
@@ -0,0 +1,71 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{mpsc, Arc, Mutex};
+use std::{fs, thread};
+
+use kaeru;
+
+use error::{Error, Result};
+use util;
+
+/// Durations of tracks in seconds by path. Tracks are probed by a background thread, so entries
+/// queued without a duration get one before they air.
+#[derive(Clone)]
+pub struct Durations {
+    known: Arc<Mutex<HashMap<String, f64>>>,
+    tx: mpsc::Sender<String>,
+}
+
+impl Durations {
+    pub fn new() -> Durations {
+        let known = Arc::new(Mutex::new(HashMap::new()));
+        let (tx, rx) = mpsc::channel::<String>();
+        let k = known.clone();
+        // Exits once the queue and with it the sender is gone
+        thread::spawn(move || for path in rx {
+            if k.lock().unwrap().contains_key(&path) {
+                continue;
+            }
+            match probe(&path) {
+                Ok(d) => {
+                    debug!("Probed duration of {}: {}s", path, d);
+                    k.lock().unwrap().insert(path, d);
+                }
+                Err(e) => debug!("Failed to probe duration of {}: {}", path, e),
+            }
+        });
+        Durations { known, tx }
+    }
+
+    pub fn get(&self, path: &str) -> Option<f64> {
+        self.known.lock().unwrap().get(path).cloned()
+    }
+
+    pub fn insert(&self, path: &str, secs: f64) {
+        self.known.lock().unwrap().insert(path.to_owned(), secs);
+    }
+
+    /// Has the duration of a track determined in the background, unless it's known already
+    pub fn probe(&self, path: &str) {
+        if self.get(path).is_none() {
+            let _ = self.tx.send(path.to_owned());
+        }
+    }
+
+    /// Forgets the durations of all tracks but the given ones
+    pub fn retain(&self, paths: &HashSet<&str>) {
+        self.known.lock().unwrap().retain(|p, _| paths.contains(&**p));
+    }
+}
+
+fn probe(path: &str) -> Result<f64> {
+    let f = fs::File::open(path).map_err(|e| Error::FileMissing(path.to_owned(), e))?;
+    let ext = util::container_ext(path)
+        .ok_or_else(|| Error::Unsupported(format!("{} has no container extension", path)))?;
+    let d = kaeru::Input::new(f, ext)?.duration();
+    let secs = d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9;
+    if secs > 0. {
+        Ok(secs)
+    } else {
+        Err(Error::Unsupported(format!("{} has no known duration", path)))
+    }
+}
@@ -1,16 +1,32 @@
-use std::{mem, fs, thread, sync};
+use std::{mem, fs, sync, thread, time};
 use std::io::{self, Read, BufReader};
-use std::collections::VecDeque;
-use config::{Config, Container};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use config::{self, Config, Container, RandomSource, StreamConfig};
 use reqwest;
 use prebuffer::PreBuffer;
 use serde_json as serde;
 use serde_json::Map;
 use serde_json::Value as JSON;
 use tc_queue;
+use broadcast::BufferData;
+use transcode::{self, Transcoder};
+use util;
+use errlog::ErrLog;
 use kaeru;
+use error::{Error, Result};
+use announce;
+use events::{Event, Events, OutputReport};
+use history;
+use library::{Library, Rotation};
+use probe::Durations;
+use faults;
 
-// 256 KiB nuffer
+// Random entries requested before settling for one which is on cooldown
+const COOLDOWN_TRIES: usize = 5;
+
+// 256 KiB buffer - inputs are always streamed through this, so memory use stays bounded
+// regardless of the input's size
 const INPUT_BUF_LEN: usize = 262144;
 
 pub struct Queue {
@@ -20,12 +36,48 @@ pub struct Queue {
     counter: u64,
     last_id: u64,
     cfg: Config,
+    transcodes: transcode::Registry,
+    fallback: Fallback,
+    events: Events,
+    errors: ErrLog,
+    /// Paths of recently played tracks, with the time they were last played
+    recent: HashMap<String, u64>,
+    library: Library,
+    /// Random picks from the library, if this queue has a filter
+    rotation: Rotation,
+    durations: Durations,
+    /// When the current track started playing
+    np_started: Option<time::Instant>,
+    /// Incremented with every change of the encoder settings
+    revision: u64,
+}
+
+/// The fallback track, encoded once for every stream at startup so that engaging it doesn't
+/// need any transcoding
+struct Fallback {
+    bufs: Vec<Vec<BufferData>>,
+    metadata: sync::Arc<kaeru::Metadata>,
+    len: u64,
+}
+
+/// Changed encoder settings of a mount along with the fallback encoded with them. Encoding the
+/// fallback takes a while, so this is prepared without holding the queue's lock.
+pub struct Encoding {
+    mount: String,
+    cfg: Config,
+    fallback: Fallback,
+    revision: u64,
 }
 
 #[derive(Clone, Debug, Deserialize, Default, PartialEq)]
 pub struct NewQueueEntry {
     pub data: Map<String, JSON>,
     pub path: String,
+    #[serde(default)]
+    pub requested_by: Option<String>,
+    /// Unix timestamp after which the entry is dropped if it hasn't aired yet
+    #[serde(default)]
+    pub expires: Option<u64>,
 }
 
 #[derive(Clone, Debug, Default, Serialize, PartialEq)]
@@ -33,39 +85,156 @@ pub struct QueueEntry {
     pub id: u64,
     pub data: Map<String, JSON>,
     pub path: String,
+    pub requested_by: Option<String>,
+    pub expires: Option<u64>,
 }
 
 #[derive(Default)]
 pub struct QueueBuffer {
     entry: QueueEntry,
     bufs: Vec<PreBuffer>,
+    input: InputStats,
+    metadata: Option<sync::Arc<kaeru::Metadata>>,
+    /// Announcement of the track for the mounts which have them, synthesized in the background
+    intro: Option<sync::mpsc::Receiver<Vec<Vec<BufferData>>>>,
+    // Must come after bufs: dropping the buffers first unblocks the transcoder so it can be torn
+    // down
+    transcoder: Option<Transcoder>,
+}
+
+#[derive(Clone, Default)]
+pub struct InputStats {
+    offset: sync::Arc<AtomicUsize>,
+    len: Option<u64>,
 }
 
 impl Queue {
-    pub fn new(cfg: Config) -> Queue {
-        let mut q = Queue {
+    pub fn new(cfg: Config, events: Events, errors: ErrLog, library: Library) -> Result<Queue> {
+        let mut q = Queue::idle(cfg, events, errors, library)?;
+        q.start(Vec::new());
+        Ok(q)
+    }
+
+    /// Creates the queue and encodes its fallback without starting to transcode anything, so that
+    /// a standby is ready to air as soon as it takes over
+    pub fn idle(cfg: Config, events: Events, errors: ErrLog, library: Library) -> Result<Queue> {
+        info!("Encoding fallback");
+        let fallback = Fallback::encode(&cfg)?;
+        let recent = load_recent(&cfg);
+        let q = Queue {
             np: Default::default(),
             next: Default::default(),
             entries: VecDeque::new(),
             cfg: cfg,
             counter: 0,
             last_id: 0,
+            transcodes: transcode::Registry::new(events.clone()),
+            fallback,
+            events,
+            errors,
+            recent,
+            library,
+            rotation: Rotation::new(),
+            durations: Durations::new(),
+            np_started: None,
+            revision: 0,
         };
-        q.start_next_tc();
-        q
+        Ok(q)
+    }
+
+    /// Queues the given entries and starts transcoding the first of them, or a random one if
+    /// there are none
+    pub fn start(&mut self, entries: Vec<NewQueueEntry>) {
+        for nqe in entries {
+            let qe = self.queue_entry_from_new(nqe);
+            if qe.duration().is_none() {
+                self.durations.probe(&qe.path);
+            }
+            self.entries.push_back(qe);
+        }
+        self.start_next_tc();
     }
 
     pub fn np(&self) -> &QueueBuffer {
         &self.np
     }
 
+    pub fn next(&self) -> &QueueBuffer {
+        &self.next
+    }
+
+    pub fn transcodes(&self) -> &transcode::Registry {
+        &self.transcodes
+    }
+
     pub fn entries(&self) -> &VecDeque<QueueEntry> {
         &self.entries
     }
 
+    /// The queued entries as shown in the API, with their duration and the unix time they're
+    /// expected to air at where known. Both are filled in as the durations are probed.
+    pub fn listing(&self) -> Vec<JSON> {
+        let duration = |e: &QueueEntry| e.duration().or_else(|| self.durations.get(&e.path));
+        let mut eta = match (self.np_started, duration(&self.np.entry)) {
+            (Some(start), Some(d)) => {
+                let elapsed = start.elapsed().as_secs() as f64;
+                Some(util::now() as f64 + (d - elapsed).max(0.))
+            }
+            _ => None,
+        };
+        self.entries.iter()
+            .map(|e| {
+                let mut json = e.serialize();
+                let d = duration(e);
+                if let Some(obj) = json.as_object_mut() {
+                    if let Some(d) = d {
+                        obj.insert("duration".to_owned(), JSON::from(d));
+                    }
+                    if let Some(t) = eta {
+                        obj.insert("eta".to_owned(), JSON::from(t as u64));
+                    }
+                }
+                // Entries after one of unknown duration can't be estimated either
+                eta = match (eta, d) {
+                    (Some(t), Some(d)) => Some(t + d),
+                    _ => None,
+                };
+                json
+            })
+            .collect()
+    }
+
+    pub fn streams(&self) -> &[StreamConfig] {
+        &self.cfg.streams
+    }
+
+    /// The config along with its revision, which changes of the encoder settings are prepared from
+    pub fn settings(&self) -> (Config, u64) {
+        (self.cfg.clone(), self.revision)
+    }
+
+    /// Swaps in encoder settings prepared from the current revision of the config. Listeners stay
+    /// connected: the settings take effect at the next track boundary, as the next track is
+    /// transcoded again with them. Returns false if the settings changed since they were prepared.
+    pub fn set_encoding(&mut self, enc: Encoding) -> bool {
+        if enc.revision != self.revision {
+            return false;
+        }
+        info!("Changing encoding of {}, effective with the next track", enc.mount);
+        self.revision += 1;
+        self.cfg = enc.cfg;
+        // Engaging the fallback must not switch back to the old settings
+        self.fallback = enc.fallback;
+        self.restart_next();
+        true
+    }
+
     pub fn push(&mut self, nqe: NewQueueEntry) {
         debug!("Inserting {:?} into queue tail!", nqe);
         let qe = self.queue_entry_from_new(nqe);
+        if qe.duration().is_none() {
+            self.durations.probe(&qe.path);
+        }
         self.entries.push_back(qe);
         if self.entries.len() == 1 {
             self.start_next_tc();
@@ -75,6 +244,9 @@ impl Queue {
     pub fn push_head(&mut self, nqe: NewQueueEntry) {
         debug!("Inserting {:?} into queue head!", nqe);
         let qe = self.queue_entry_from_new(nqe);
+        if qe.duration().is_none() {
+            self.durations.probe(&qe.path);
+        }
         self.entries.push_front(qe);
         self.start_next_tc();
     }
@@ -101,15 +273,48 @@ impl Queue {
         }
     }
 
+    /// Drops the entries whose expiry has passed before they aired
+    pub fn expire(&mut self) {
+        let now = util::now();
+        let expired = |e: &QueueEntry| e.expires.map(|t| t <= now).unwrap_or(false);
+        if !self.entries.iter().any(&expired) {
+            return;
+        }
+        let mut restart = false;
+        let entries = mem::replace(&mut self.entries, VecDeque::new());
+        for e in entries {
+            if !expired(&e) {
+                self.entries.push_back(e);
+                continue;
+            }
+            info!("Dropping expired entry {:?}", e);
+            restart |= e == self.next.entry;
+            self.events.emit(Event::Expired { entry: e.serialize() });
+        }
+        if restart {
+            self.start_next_tc();
+        }
+    }
+
     pub fn get_next_tc(&mut self) -> Vec<PreBuffer> {
         debug!("Extracting current pre-transcode!");
         // Swap next into np, then clear next and extract np buffers
         mem::swap(&mut self.next, &mut self.np);
         self.next = Default::default();
+        self.np_started = Some(time::Instant::now());
         // Pop queue head if its the same as np, and start next transcode
         if self.entries.front().map(|e| *e == self.np.entry).unwrap_or(false) {
             self.entries.pop_front();
         }
+        if let (Some(cooldown), false) = (self.cfg.queue.cooldown, self.np.entry.data.is_empty()) {
+            let now = util::now();
+            self.recent.retain(|_, t| *t + cooldown * 60 > now);
+            self.recent.insert(self.np.entry.path.clone(), now);
+        }
+        {
+            let paths: HashSet<_> = self.entries.iter().chain(Some(&self.np.entry)).map(|e| &*e.path).collect();
+            self.durations.retain(&paths);
+        }
         mem::replace(&mut self.np.bufs, Vec::new())
     }
 
@@ -118,51 +323,124 @@ impl Queue {
         let mut tries = 0;
         loop {
             if tries == 5 {
-                use std::borrow::Borrow;
-                let buf = {
-                    let b: &Vec<u8> = self.cfg.queue.fallback.0.borrow();
-                    io::Cursor::new(b.clone())
-                };
-                // TODO: Make this less retarded - Rust can't deal with two levels of dereference
-                let ct = &self.cfg.queue.fallback.1.clone();
                 warn!("Using fallback");
-                let tc = self.initiate_transcode(buf, ct).unwrap();
-                self.next = QueueBuffer {
-                    bufs: tc,
-                    entry: self.queue_entry_from_new(NewQueueEntry { data: Map::new(), path: "fallback".to_owned() }),
-                };
+                let entry = self.queue_entry_from_new(NewQueueEntry { data: Map::new(), path: "fallback".to_owned(), ..Default::default() });
+                self.events.emit(self.fallback.report(&entry, &self.cfg));
+                self.next = self.fallback.buffer(entry);
+                self.add_announcement();
                 return;
             }
             tries += 1;
-            if let Some(qe) = self.next_buffer() {
-                match fs::File::open(&qe.path) {
-                    Ok(f) => {
-                        let ext = if let Some(e) = qe.path.split('.').last() { e } else { continue };
-                        match self.initiate_transcode(f, ext) {
-                            Ok(tc) => {
-                                self.next = QueueBuffer {
-                                    bufs: tc,
-                                    entry: qe.clone(),
-                                };
-                                return;
-                            },
-                            Err(e) => {
-                                warn!("Failed to start transcode: {}", e);
-                                continue;
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        warn!("Failed to open queue entry {:?}: {}", qe, e);
-                        continue;
-                    }
+            let qe = match self.next_buffer() {
+                Ok(qe) => qe,
+                Err(e) => {
+                    self.errors.report("next entry", &e);
+                    continue;
+                }
+            };
+            match self.transcode_entry(&qe) {
+                Ok(qb) => {
+                    self.next = qb;
+                    self.add_announcement();
+                    return;
+                }
+                Err(e) => {
+                    debug!("Failed to start transcode of queue entry {:?}", qe);
+                    self.errors.report("transcode", &e);
+                    continue;
+                }
+            }
+        }
+    }
+
+    /// Transcodes the next entry again, picking up changed stream settings
+    fn restart_next(&mut self) {
+        let entry = self.next.entry.clone();
+        // The fallback has no blob
+        if entry.data.is_empty() {
+            self.next = self.fallback.buffer(entry);
+        } else {
+            match self.transcode_entry(&entry) {
+                Ok(qb) => self.next = qb,
+                Err(e) => {
+                    self.errors.report("transcode", &e);
+                    self.start_next_tc();
+                    return;
                 }
             }
         }
+        self.add_announcement();
     }
 
-    fn next_buffer(&mut self) -> Option<QueueEntry> {
-        self.next_queue_buffer().or_else(|| self.random_buffer())
+    /// Has the announcement of the upcoming track synthesized and encoded in the background, for
+    /// the mounts which have announcements enabled
+    fn add_announcement(&mut self) {
+        let acfg = match self.cfg.announce {
+            Some(ref a) => a.clone(),
+            None => return,
+        };
+        // Neither the fallback nor the start of playout are worth announcing
+        if !self.cfg.streams.iter().any(|s| s.announce) || self.np.entry.data.is_empty()
+            || self.next.entry.data.is_empty() {
+            return;
+        }
+        let text = announce::text(&acfg,
+                                  (&self.np.entry, self.np.metadata.as_ref().map(|m| &**m)),
+                                  (&self.next.entry, self.next.metadata.as_ref().map(|m| &**m)));
+        let cfg = self.cfg.clone();
+        let errors = self.errors.clone();
+        let (tx, rx) = sync::mpsc::channel();
+        self.next.intro = Some(rx);
+        // Nobody waits for the announcement if next is replaced in the meantime
+        thread::spawn(move || {
+            let res = announce::synthesize(&acfg, &text)
+                .and_then(|data| encode_announcement(sync::Arc::new(data), &acfg.format, &cfg));
+            match res {
+                Ok(bufs) => {
+                    let _ = tx.send(bufs);
+                }
+                Err(e) => errors.report("announcement", &e),
+            }
+        });
+    }
+
+    /// Returns the announcement to play before the track which starts playing, if it's ready by
+    /// now. Otherwise the track plays without it rather than holding up playout.
+    pub fn announcement(&mut self) -> Option<Vec<PreBuffer>> {
+        let bufs = match self.np.intro.take().map(|rx| rx.try_recv()) {
+            Some(Ok(b)) => b,
+            Some(Err(sync::mpsc::TryRecvError::Empty)) => {
+                warn!("Announcement of {} isn't ready, playing it without", self.np.entry.path);
+                return None;
+            }
+            // Synthesizing it failed, which has been reported already
+            Some(Err(sync::mpsc::TryRecvError::Disconnected)) | None => return None,
+        };
+        let metadata = self.np.metadata.clone()?;
+        Some(bufs.iter()
+            .map(|b| {
+                let mut pb = PreBuffer::new(tc_queue::from_cache(b), metadata.clone());
+                pb.announcement = true;
+                pb
+            })
+            .collect())
+    }
+
+    fn transcode_entry(&mut self, qe: &QueueEntry) -> Result<QueueBuffer> {
+        let f = fs::File::open(&qe.path).map_err(|e| Error::FileMissing(qe.path.clone(), e))?;
+        let len = f.metadata().ok().map(|m| m.len());
+        let ext = match util::container_ext(&qe.path) {
+            Some(e) => e,
+            None => return Err(Error::Unsupported(format!("{} has no container extension", qe.path))),
+        };
+        self.initiate_transcode(qe.clone(), f, len, ext)
+    }
+
+    fn next_buffer(&mut self) -> Result<QueueEntry> {
+        match self.next_queue_buffer() {
+            Some(qe) => Ok(qe),
+            None => self.random_buffer(),
+        }
     }
 
     fn next_queue_buffer(&mut self) -> Option<QueueEntry> {
@@ -173,61 +451,289 @@ impl Queue {
         e
     }
 
-    fn random_buffer(&mut self) -> Option<QueueEntry> {
-        let mut body = String::new();
-        let res = reqwest::get(&self.cfg.queue.random.clone())
-            .ok()
-            .and_then(|mut r| r.read_to_string(&mut body).ok())
-            .and_then(|_| serde::from_str(&body).ok())
-            .and_then(|v| NewQueueEntry::deserialize(v))
-            .map(|v| self.queue_entry_from_new(v));
-        if res.is_some() {
-            info!("Using random entry {:?}", res.as_ref().unwrap());
-        }
-        res
+    fn random_buffer(&mut self) -> Result<QueueEntry> {
+        let nqe = {
+            let (cfg, rotation, library) = (&self.cfg, &mut self.rotation, &self.library);
+            let mut next = || random_entry(cfg, rotation, library);
+            match cfg.queue.cooldown {
+                Some(c) => pick_cooled(next, &self.recent, c * 60, util::now())?,
+                None => next()?,
+            }
+        };
+        info!("Using random entry {:?}", nqe);
+        Ok(self.queue_entry_from_new(nqe))
     }
 
-    fn initiate_transcode<T: io::Read + Send>(&mut self, s: T, container: &str) -> kaeru::Result<Vec<PreBuffer>> {
+    fn initiate_transcode<T: io::Read + Send>(&mut self, entry: QueueEntry, s: T, len: Option<u64>, container: &str) -> Result<QueueBuffer> {
         let mut prebufs = Vec::new();
-        let input = kaeru::Input::new(BufReader::with_capacity(INPUT_BUF_LEN, s), container)?;
-        let metadata = sync::Arc::new(input.metadata());
-        let mut gb = kaeru::GraphBuilder::new(input)?;
+        let mut tokens = Vec::new();
+        let mut outputs = Vec::new();
+        let reader = util::TrackedReader::new(s);
+        let input_stats = InputStats { offset: reader.offset(), len };
+        let mut input = kaeru::Input::new(BufReader::with_capacity(INPUT_BUF_LEN, reader), container)?;
+        let duration = input.duration();
+        if duration.as_secs() > 0 {
+            self.durations.insert(&entry.path, duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9);
+        }
+        let mut metadata = input.metadata();
+        if let (true, Some(r)) = (self.cfg.radio.shoutouts, entry.requested_by.as_ref()) {
+            let title = format!("{} (requested by {})", metadata.title.as_ref().unwrap_or(&entry.path), r);
+            input.set_metadata("title", &title)?;
+            metadata.title = Some(title);
+        }
+        let metadata = sync::Arc::new(metadata);
+        let mut gb = graph(input, &self.cfg)?;
         for s in self.cfg.streams.iter() {
             let (tx, rx) = tc_queue::new();
-            let ct = match s.container {
-                Container::Ogg => "ogg",
-                Container::MP3 => "mp3",
-                Container::FLAC => "flac",
-            };
-            let output = kaeru::Output::new(tx, ct, s.codec, s.bitrate)?;
+            let mut output = kaeru::Output::new(tx, s.container.as_str(), s.codec, s.bitrate)?;
+            output.set_surround(s.surround);
             gb.add_output(output)?;
+            tokens.push(rx.done.clone());
+            outputs.push((s.mount.clone(), rx.stats.clone()));
             prebufs.push(PreBuffer::new(rx, metadata.clone()));
         }
         let g = gb.build()?;
-        thread::spawn(move || {
-            debug!("Starting transcode");
-            match g.run() {
-                Ok(()) => { }
-                Err(e) => { debug!("transcode completed with err: {}", e) }
-            }
-            debug!("Completed transcode");
-        });
+        let transcoder = self.transcodes.spawn(&entry, g, tokens, outputs);
         self.counter += 1;
-        Ok(prebufs)
+        Ok(QueueBuffer {
+            entry,
+            bufs: prebufs,
+            input: input_stats,
+            metadata: Some(metadata),
+            intro: None,
+            transcoder: Some(transcoder),
+        })
     }
 
     fn queue_entry_from_new(&mut self, nqe: NewQueueEntry) -> QueueEntry {
         self.last_id += 1;
-        QueueEntry { id: self.last_id, data: nqe.data, path: nqe.path }
+        QueueEntry {
+            id: self.last_id,
+            data: nqe.data,
+            path: nqe.path,
+            requested_by: nqe.requested_by,
+            expires: nqe.expires,
+        }
+    }
+}
+
+impl Encoding {
+    /// Prepares new settings for a mount from the queue's settings, None if the queue doesn't
+    /// play it
+    pub fn prepare(settings: (Config, u64), mount: &str, bitrate: Option<i64>, codec: Option<kaeru::AVCodecID>)
+                   -> Result<Option<Encoding>> {
+        let (cfg, revision) = settings;
+        let cfg = match with_encoding(cfg, mount, bitrate, codec)? {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+        let fallback = Fallback::encode(&cfg)?;
+        Ok(Some(Encoding { mount: mount.to_owned(), cfg, fallback, revision }))
+    }
+}
+
+/// Applies new encoder settings to a mount of the config, None if it doesn't have the mount
+fn with_encoding(mut cfg: Config, mount: &str, bitrate: Option<i64>, codec: Option<kaeru::AVCodecID>)
+                 -> Result<Option<Config>> {
+    {
+        let s = match cfg.streams.iter_mut().find(|s| s.mount == mount) {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+        if let Some(c) = codec {
+            // Ogg streams are chained at every track, so they can switch codecs in between.
+            // Other containers would need the listeners to reconnect.
+            let fits = match s.container {
+                Container::Ogg => c != kaeru::AVCodecID::AV_CODEC_ID_MP3,
+                _ => c == s.codec,
+            };
+            if !fits {
+                return Err(Error::Unsupported(format!("{} can't be switched to {}", s.mount, config::codec_name(c))));
+            }
+            s.codec = c;
+        }
+        if bitrate.is_some() {
+            s.bitrate = bitrate;
+        }
     }
+    Ok(Some(cfg))
+}
+
+impl Fallback {
+    fn encode(cfg: &Config) -> Result<Fallback> {
+        let data = cfg.queue.fallback.0.clone();
+        let len = data.len() as u64;
+        let input = kaeru::Input::new(util::SharedReader::new(data), &cfg.queue.fallback.1)?;
+        let metadata = sync::Arc::new(input.metadata());
+        let bufs = encode_cached(graph(input, cfg)?, &cfg.streams.iter().collect::<Vec<_>>())?;
+        Ok(Fallback { bufs, metadata, len })
+    }
+
+    /// The fallback isn't transcoded, so its report only has the size of the cached buffers
+    fn report(&self, entry: &QueueEntry, cfg: &Config) -> Event {
+        Event::TrackReport {
+            entry: entry.serialize(),
+            duration: None,
+            realtime_factor: None,
+            decode_errors: 0,
+            gain: None,
+            outputs: cfg.streams.iter().zip(self.bufs.iter())
+                .map(|(s, b)| OutputReport { mount: s.mount.clone(), bytes: b.iter().map(|d| d.frame().len()).sum() })
+                .collect(),
+            fallback: true,
+            cancelled: false,
+            error: None,
+        }
+    }
+
+    fn buffer(&self, entry: QueueEntry) -> QueueBuffer {
+        QueueBuffer {
+            entry,
+            bufs: self.bufs.iter()
+                .map(|b| PreBuffer::new(tc_queue::from_cache(b), self.metadata.clone()))
+                .collect(),
+            input: InputStats { offset: sync::Arc::new(AtomicUsize::new(self.len as usize)), len: Some(self.len) },
+            metadata: Some(self.metadata.clone()),
+            intro: None,
+            transcoder: None,
+        }
+    }
+}
+
+/// Loads the tracks played within the cooldown from the history, so that it survives restarts
+fn load_recent(cfg: &Config) -> HashMap<String, u64> {
+    let mut recent = HashMap::new();
+    if let (Some(cooldown), Some(path)) = (cfg.queue.cooldown, cfg.queue.history.as_ref()) {
+        match history::load(path, util::now().saturating_sub(cooldown * 60), u64::max_value()) {
+            Ok(plays) => {
+                for p in plays {
+                    recent.insert(p.path, p.time);
+                }
+            }
+            Err(e) => debug!("No recent plays loaded from history: {}", e),
+        }
+    }
+    recent
+}
+
+/// Gets an entry from the queue's random source, refusing blocked ones
+fn random_entry(cfg: &Config, rotation: &mut Rotation, library: &Library) -> Result<NewQueueEntry> {
+    let nqe = match cfg.queue.random {
+        RandomSource::Api(ref url) => fetch_random(url)?,
+        RandomSource::Library(ref filter) => rotation.next(library, filter)
+            .ok_or_else(|| Error::Config(format!("no tracks in the library match the queue's filter")))?,
+    };
+    if let Some(reason) = cfg.blocklist.check(&nqe.path, &nqe.data) {
+        return Err(Error::Blocked(format!("random entry {}: {}", nqe.path, reason)));
+    }
+    Ok(nqe)
+}
+
+/// Gets random entries until one wasn't played within the cooldown, settling for the least
+/// recently played one if none of them qualify
+fn pick_cooled<F>(mut next: F, recent: &HashMap<String, u64>, cooldown: u64, now: u64) -> Result<NewQueueEntry>
+    where F: FnMut() -> Result<NewQueueEntry>
+{
+    let mut oldest: Option<(u64, NewQueueEntry)> = None;
+    for _ in 0..COOLDOWN_TRIES {
+        let nqe = next()?;
+        let played = match recent.get(&nqe.path).cloned() {
+            Some(t) if t + cooldown > now => t,
+            _ => return Ok(nqe),
+        };
+        debug!("Random entry {} was played recently, trying another", nqe.path);
+        if oldest.as_ref().map(|&(t, _)| played < t).unwrap_or(true) {
+            oldest = Some((played, nqe));
+        }
+    }
+    let (_, nqe) = oldest.unwrap();
+    warn!("All random entries were played recently, using {}", nqe.path);
+    Ok(nqe)
+}
+
+fn fetch_random(url: &str) -> Result<NewQueueEntry> {
+    let mut body = String::new();
+    reqwest::get(url)?
+        .read_to_string(&mut body)
+        .map_err(|e| Error::BadResponse(format!("failed to read random entry: {}", e)))?;
+    if faults::garbage_random() {
+        body = "garbage".to_owned();
+    }
+    let json = serde::from_str(&body)
+        .map_err(|e| Error::BadResponse(format!("random entry is not valid json: {}", e)))?;
+    NewQueueEntry::deserialize(json)
+        .ok_or_else(|| Error::BadResponse(format!("random entry must contain path")))
+}
+
+/// Starts the graph for an input, running it through the [normalize] chain if configured. Every
+/// source of audio goes through here, so that switching between them doesn't change loudness.
+pub fn graph(input: kaeru::Input, cfg: &Config) -> Result<kaeru::GraphBuilder> {
+    let mut gb = kaeru::GraphBuilder::new(input)?;
+    if let Some(ref n) = cfg.normalize {
+        for (name, args) in n.filters() {
+            gb.add_filter(name, &args)?;
+        }
+    }
+    Ok(gb)
+}
+
+/// Encodes the whole input for each of the streams up front, for replaying through
+/// `tc_queue::from_cache`
+fn encode_cached(mut gb: kaeru::GraphBuilder, streams: &[&StreamConfig]) -> Result<Vec<Vec<BufferData>>> {
+    let mut collected = Vec::new();
+    for s in streams {
+        let (sink, bufs) = tc_queue::Collector::new();
+        let mut output = kaeru::Output::new(sink, s.container.as_str(), s.codec, s.bitrate)?;
+        output.set_surround(s.surround);
+        gb.add_output(output)?;
+        collected.push(bufs);
+    }
+    // The collectors are dropped along with the graph, so all buffers are in by the time run
+    // returns
+    gb.build()?.run()?;
+    Ok(collected.into_iter()
+        .map(|b| {
+            let mut b = b.lock().unwrap();
+            mem::replace(&mut *b, Vec::new())
+        })
+        .collect())
+}
+
+/// Encodes an announcement for every stream, in their order. The mounts without announcements get
+/// silence of the same length, so that all mounts stay in step while it plays.
+fn encode_announcement(data: sync::Arc<Vec<u8>>, format: &str, cfg: &Config) -> Result<Vec<Vec<BufferData>>> {
+    let (voiced, silent): (Vec<&StreamConfig>, Vec<&StreamConfig>) = cfg.streams.iter().partition(|s| s.announce);
+    let input = kaeru::Input::new(util::SharedReader::new(data.clone()), format)?;
+    let mut voiced = encode_cached(graph(input, cfg)?, &voiced)?.into_iter();
+    let mut silent = if silent.is_empty() {
+        Vec::new().into_iter()
+    } else {
+        let input = kaeru::Input::new(util::SharedReader::new(data), format)?;
+        let mut gb = graph(input, cfg)?;
+        gb.add_filter("volume", "volume=0")?;
+        encode_cached(gb, &silent)?.into_iter()
+    };
+    Ok(cfg.streams.iter()
+        .map(|s| if s.announce { voiced.next() } else { silent.next() }.unwrap_or_default())
+        .collect())
 }
 
 impl NewQueueEntry {
     pub fn deserialize(json: JSON) -> Option<NewQueueEntry> {
         match json {
             JSON::Object(o) => {
+                let requested_by = match o.get("requested_by") {
+                    Some(&JSON::String(ref r)) => Some(r.clone()),
+                    _ => None,
+                };
+                // Relative ttls are resolved on insertion, so that they survive being mirrored
+                let expires = match (o.get("expires").and_then(|e| e.as_u64()), o.get("ttl").and_then(|t| t.as_u64())) {
+                    (Some(e), _) => Some(e),
+                    (None, Some(ttl)) => Some(util::now() + ttl),
+                    (None, None) => None,
+                };
                 match o.get("path").cloned() {
-                    Some(JSON::String(p)) => Some(NewQueueEntry { data: o, path: p }),
+                    Some(JSON::String(p)) => Some(NewQueueEntry { data: o, path: p, requested_by, expires }),
                     _ => None,
                 }
             }
@@ -237,8 +743,20 @@ impl NewQueueEntry {
 }
 
 impl QueueEntry {
+    /// Duration in seconds, if the blob came with one
+    pub fn duration(&self) -> Option<f64> {
+        self.data.get("duration").and_then(|d| d.as_f64())
+    }
+
     pub fn serialize(&self) -> JSON {
-        JSON::Object(self.data.clone())
+        let mut data = self.data.clone();
+        if let Some(ref r) = self.requested_by {
+            data.insert("requested_by".to_owned(), JSON::String(r.clone()));
+        }
+        if let Some(e) = self.expires {
+            data.insert("expires".to_owned(), JSON::from(e));
+        }
+        JSON::Object(data)
     }
 }
 
@@ -246,4 +764,57 @@ impl QueueBuffer {
     pub fn entry(&self) -> &QueueEntry {
         &self.entry
     }
+
+    pub fn input(&self) -> &InputStats {
+        &self.input
+    }
+}
+
+impl InputStats {
+    /// Number of bytes of the input read so far
+    pub fn offset(&self) -> usize {
+        self.offset.load(Ordering::Relaxed)
+    }
+
+    pub fn length(&self) -> Option<u64> {
+        self.len
+    }
+}
+
+#[test]
+fn test_cooldown() {
+    let entry = |p: &str| -> Result<NewQueueEntry> { Ok(NewQueueEntry { path: p.to_owned(), ..Default::default() }) };
+    let mut recent = HashMap::new();
+    recent.insert("a".to_owned(), 1000);
+    recent.insert("b".to_owned(), 900);
+    recent.insert("c".to_owned(), 100);
+    // c's cooldown has passed
+    let mut picks = vec!["a", "b", "c", "a"].into_iter();
+    assert_eq!(pick_cooled(|| entry(picks.next().unwrap()), &recent, 600, 1200).unwrap().path, "c");
+    assert_eq!(picks.next(), Some("a"));
+    let mut picks = vec!["a", "b"].into_iter().cycle();
+    assert_eq!(pick_cooled(|| entry(picks.next().unwrap()), &recent, 600, 1200).unwrap().path, "b");
+    let mut picks = vec!["d"].into_iter();
+    assert_eq!(pick_cooled(|| entry(picks.next().unwrap()), &recent, 600, 1200).unwrap().path, "d");
+}
+
+#[test]
+fn test_with_encoding() {
+    let fallback = ::std::env::temp_dir().join("kawa-test-fallback.ogg");
+    fs::write(&fallback, b"").unwrap();
+    let cfg = config::parse_config(&format!("[radio]\nport = 8001\nname = \"test\"\n[api]\nport = 4040\n\
+                                             [queue]\nrandom_song_api = \"http://localhost/random\"\n\
+                                             np = \"http://localhost/np\"\nfallback = {:?}\n\
+                                             [[streams]]\nmount = \"a.ogg\"\ncontainer = \"ogg\"\n\
+                                             [[streams]]\nmount = \"b.mp3\"\ncontainer = \"mp3\"\n",
+                                            fallback.to_str().unwrap())).unwrap();
+    let vorbis = Some(kaeru::AVCodecID::AV_CODEC_ID_VORBIS);
+    let changed = with_encoding(cfg.clone(), "a.ogg", Some(96000), vorbis).unwrap().unwrap();
+    assert_eq!(changed.streams[0].codec, kaeru::AVCodecID::AV_CODEC_ID_VORBIS);
+    assert_eq!(changed.streams[0].bitrate, Some(96000));
+    assert_eq!(changed.streams[1].bitrate, None);
+    // Only ogg can switch codecs between tracks
+    assert!(with_encoding(cfg.clone(), "b.mp3", None, vorbis).is_err());
+    assert!(with_encoding(cfg.clone(), "b.mp3", Some(128000), None).unwrap().is_some());
+    assert!(with_encoding(cfg, "c.mp3", Some(128000), None).unwrap().is_none());
 }
@@ -1,24 +1,74 @@
-use std::sync::{Arc, Mutex};
-use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{self, Receiver, Sender};
-use std::{thread, time};
+use std::collections::HashSet;
+use std::{io, thread, time};
 
 use reqwest;
 
 use queue::{Queue, QueueEntry};
-use api::{ApiMessage, QueuePos};
+use api::{self, ApiMessage, QueuePos};
 use config::Config;
 use prebuffer::PreBuffer;
 use broadcast::{Buffer, BufferData};
 use tc_queue::BufferRes;
-use amy;
+use history;
+use output::{self, OutputSink};
+use error::{Error, Result};
+use errlog::ErrLog;
+use poll;
 
 struct RadioConn {
+    mid: usize,
     tx: Sender<PreBuffer>,
 }
 
 const SYNC_AHEAD: u64 = 1;
 const MAX_FALL_BEHIND: u64 = 2;
+// Seconds between checks for expired queue entries
+const EXPIRY_INTERVAL: u64 = 1;
+
+/// Makes all mounts switch to the next track together. Every mount drains its buffer at a
+/// slightly different rate, so without this they'd drift apart on track boundaries.
+struct TrackBarrier {
+    count: usize,
+    // (mounts waiting, generation)
+    state: Mutex<(usize, u64)>,
+    cvar: Condvar,
+}
+
+impl TrackBarrier {
+    fn new(count: usize) -> TrackBarrier {
+        TrackBarrier {
+            count,
+            state: Mutex::new((0, 0)),
+            cvar: Condvar::new(),
+        }
+    }
+
+    /// Waits for all other mounts to reach the track boundary. Gives up after the timeout, so
+    /// that a stalled mount can't hold up the others.
+    fn wait(&self, timeout: time::Duration) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let generation = state.1;
+        state.0 += 1;
+        if state.0 >= self.count {
+            *state = (0, generation + 1);
+            self.cvar.notify_all();
+            return true;
+        }
+        let deadline = time::Instant::now() + timeout;
+        while state.1 == generation {
+            let now = time::Instant::now();
+            if now >= deadline {
+                state.0 -= 1;
+                return false;
+            }
+            state = self.cvar.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        true
+    }
+}
 
 struct Syncer {
     last_pts: f64,
@@ -75,142 +125,277 @@ impl Syncer {
 impl RadioConn {
     fn new(
         mid: usize,
-        btx: amy::Sender<Buffer>,
+        sink: Box<OutputSink>,
+        barrier: Arc<TrackBarrier>,
+        errors: ErrLog,
     ) -> RadioConn {
         let (tx, rx) = mpsc::channel();
 
         thread::spawn(move || {
-            play(rx, mid, btx);
+            play(rx, mid, sink, barrier, errors);
         });
         RadioConn {
+            mid,
             tx: tx,
         }
     }
 
-    fn replace_buffer(&mut self, buffer: PreBuffer) {
-        self.tx.send(buffer).unwrap();
+    /// Hands the mount its next buffer, failing if its playout thread is gone
+    fn replace_buffer(&mut self, buffer: PreBuffer) -> Result<()> {
+        self.tx.send(buffer).map_err(|_| Error::Unavailable)
     }
 }
 
-pub fn play(buffer_rec: Receiver<PreBuffer>, mid: usize, btx: amy::Sender<Buffer>) {
+fn play(buffer_rec: Receiver<PreBuffer>, mid: usize, mut sink: Box<OutputSink>, barrier: Arc<TrackBarrier>,
+        errors: ErrLog) {
     debug!("Awaiting initial buffer");
-    let mut pb = buffer_rec.recv().unwrap();
+    // The radio loop only goes away along with the mount
+    let mut pb = match buffer_rec.recv() {
+        Ok(pb) => pb,
+        Err(_) => return,
+    };
     let mut syncer = Syncer::new();
     loop {
-        match pb.buffer.next_buf() {
+        // Skipping an announcement ends it early, the track is only skipped by its transcode
+        // stopping
+        let res = if pb.announcement && pb.buffer.done.load(Ordering::Acquire) {
+            BufferRes::Done
+        } else {
+            pb.buffer.next_buf()
+        };
+        match res {
             BufferRes::Data(BufferData::Frame { data, pts } ) => {
                 syncer.update(pts);
-                btx.send(Buffer::new(mid, BufferData::Frame { data, pts })).unwrap();
+                send(&mut sink, mid, BufferData::Frame { data, pts }, &errors);
                 syncer.sync();
             }
             BufferRes::Data(b @ BufferData::Header(_) ) => {
                 syncer.new_song();
-                btx.send(Buffer::new(mid, b)).unwrap();
+                if !pb.announcement {
+                    if let Err(e) = sink.metadata(&title(&pb)) {
+                        errors.report(&format!("mount {} metadata", mid), &e);
+                    }
+                }
+                send(&mut sink, mid, b, &errors);
             }
-            BufferRes::Data(b @ BufferData::Trailer(_) ) => {
-                btx.send(Buffer::new(mid, b)).unwrap();
+            BufferRes::Data(b) => {
+                send(&mut sink, mid, b, &errors);
             }
             BufferRes::Timeout => {
                 if syncer.should_skip() {
                     debug!("Buffer recv timeout, skipping!");
                     pb.buffer.done.store(true, Ordering::Release);
-                    pb = buffer_rec.recv().unwrap();
+                    pb = match buffer_rec.recv() {
+                        Ok(pb) => pb,
+                        Err(_) => return,
+                    };
                     syncer.done();
+                    align(&barrier, mid);
+                    sync(&mut sink, mid, &errors);
                     debug!("Received next buffer, moving on!");
                 }
             }
             BufferRes::Done => {
                 pb.buffer.done.store(true, Ordering::Release);
                 debug!("Buffer drained, waiting for next!");
-                pb = buffer_rec.recv().unwrap();
+                pb = match buffer_rec.recv() {
+                    Ok(pb) => pb,
+                    Err(_) => return,
+                };
                 debug!("Received next buffer, syncing for remaining time!");
                 syncer.done();
+                align(&barrier, mid);
+                sync(&mut sink, mid, &errors);
                 debug!("Sync complete, resuming!");
             }
         }
     }
 }
 
+/// Sends to the sink, reconnecting it if that fails
+fn send(sink: &mut Box<OutputSink>, mid: usize, data: BufferData, errors: &ErrLog) {
+    if let Err(e) = sink.send(data) {
+        errors.report(&format!("mount {} output", mid), &e);
+        if let Err(e) = sink.reconnect() {
+            debug!("Mount {} failed to reconnect its output ({}): {}", mid, e.code(), e);
+        }
+    }
+}
+
+fn sync(sink: &mut Box<OutputSink>, mid: usize, errors: &ErrLog) {
+    if let Err(e) = sink.sync() {
+        errors.report(&format!("mount {} output", mid), &e);
+    }
+}
+
+/// Formats the title of a buffer's track the way ICY metadata usually has it
+fn title(pb: &PreBuffer) -> String {
+    match (pb.metadata.artist.as_ref(), pb.metadata.title.as_ref()) {
+        (Some(a), Some(t)) => format!("{} - {}", a, t),
+        (None, Some(t)) => t.clone(),
+        _ => String::new(),
+    }
+}
+
+fn align(barrier: &TrackBarrier, mid: usize) {
+    if !barrier.wait(time::Duration::from_secs(MAX_FALL_BEHIND)) {
+        debug!("Mount {} timed out waiting for the other mounts at track boundary", mid);
+    }
+}
+
 pub fn start_streams(cfg: Config,
                      queue: Arc<Mutex<Queue>>,
                      updates: Receiver<ApiMessage>,
-                     btx: amy::Sender<Buffer>,
+                     btx: poll::Sender<Buffer>,
+                     listeners: api::Listeners,
+                     errors: ErrLog,
                      ) {
-    let mut rconns: Vec<_> = cfg.streams.iter().enumerate()
-        .map(|(id, _)| {
-            RadioConn::new(id,
-                             btx.try_clone().unwrap(),
+    let barrier = Arc::new(TrackBarrier::new(cfg.streams.len()));
+    let mut rconns: Vec<_> = cfg.streams.iter()
+        .map(|s| {
+            RadioConn::new(s.id,
+                             output::sink(s.id, s, &btx, &errors),
+                             barrier.clone(),
+                             errors.clone(),
                              )
         })
         .collect();
+    let mounts: HashSet<&str> = cfg.streams.iter().map(|s| &*s.mount).collect();
 
     loop {
         debug!("Extracting next buffer");
-        let prebuffers = queue.lock().unwrap().get_next_tc();
+        let (prebuffers, announcement) = {
+            let mut q = queue.lock().unwrap();
+            let prebuffers = q.get_next_tc();
+            (prebuffers, q.announcement())
+        };
+
+        // The announcement is paced like a track of its own, with the mounts without one playing
+        // silence of the same length. The track's transcode waits for its buffers to be taken
+        // meanwhile, so all mounts start it together.
+        if let Some(announcement) = announcement {
+            debug!("Dispatching announcement");
+            let tokens = dispatch(&mut rconns, announcement, &errors);
+            play_item(&tokens, &updates, &queue, &cfg, &btx, &errors);
+        }
 
         debug!("Dispatching new buffers");
-        // The order is guarenteed to be correct because we always iterate by the config
-        // ordering.
-        let tokens: Vec<_> = rconns.iter_mut().zip(prebuffers.into_iter())
-            .map(|(rconn, pb)| {
-                let tok = pb.buffer.done.clone();
-                rconn.replace_buffer(pb);
-                tok
-            }).collect();
+        let tokens = dispatch(&mut rconns, prebuffers, &errors);
 
         debug!("Broadcasting np");
         let np = queue.lock().unwrap().np().entry().clone();
+        // Entries can carry a cue which is injected as the track starts
+        if let Some(cue) = np.data.get("cue").and_then(|c| c.as_str()) {
+            if let Err(e) = send_cue(&cfg, &btx, cue) {
+                errors.report("cue", &e);
+            }
+        }
+        // The fallback has no blob and isn't worth reporting
+        if let (Some(path), false) = (cfg.queue.history.as_ref(), np.data.is_empty()) {
+            // Other queues' mounts play something else to their listeners
+            let count = listeners.lock().unwrap().values().filter(|l| mounts.contains(&*l.mount)).count();
+            let play = history::Play::new(&np, count);
+            if let Err(e) = history::record(path, &play) {
+                errors.report("history", &Error::FileMissing(path.clone(), e));
+            }
+        }
         if let Err(e) = broadcast_np(&cfg.queue.np, np) {
-            warn!("Failed to broadcast np: {}", e);
+            errors.report("np broadcast", &e);
         }
 
         queue.lock().unwrap().start_next_tc();
         debug!("Entering main loop");
+        play_item(&tokens, &updates, &queue, &cfg, &btx, &errors);
+    }
+}
 
-        // Song activity loop - ensures that the song is properly transcoding and handles any sort
-        // of API message that gets received in the meanwhile
-        loop {
-            // If any prebuffer completes, just move on to next song. We want to minimize downtime
-            // even if it means some songs get cut off early
-            if tokens.iter().any(|tok| tok.load(Ordering::Acquire)) {
-                break;
-            } else {
-                if let Ok(msg) = updates.try_recv() {
-                    // Keep all these operations local just incase
-                    // anything complex might need to happen in the future.
-                    debug!("Received API message {:?}", msg);
-                    match msg {
-                        ApiMessage::Skip => {
-                            for token in tokens {
-                                token.store(true, Ordering::Release);
-                            }
-                            break;
-                        }
-                        ApiMessage::Clear => {
-                            queue.lock().unwrap().clear();
-                        }
-                        ApiMessage::Insert(QueuePos::Head, qe) => {
-                            queue.lock().unwrap().push_head(qe);
-                        }
-                        ApiMessage::Insert(QueuePos::Tail, qe) => {
-                            queue.lock().unwrap().push(qe);
-                        }
-                        ApiMessage::Remove(QueuePos::Head) => {
-                            queue.lock().unwrap().pop_head();
+/// Dispatches a buffer to each mount, returning the tokens which are set once they're done. Mounts
+/// whose playout thread is gone are left out, their buffers would end every track right away.
+fn dispatch(rconns: &mut [RadioConn], prebuffers: Vec<PreBuffer>, errors: &ErrLog) -> Vec<Arc<AtomicBool>> {
+    // The order is guarenteed to be correct because we always iterate by the config
+    // ordering.
+    rconns.iter_mut().zip(prebuffers.into_iter())
+        .filter_map(|(rconn, pb)| {
+            let tok = pb.buffer.done.clone();
+            match rconn.replace_buffer(pb) {
+                Ok(()) => Some(tok),
+                Err(e) => {
+                    errors.report(&format!("mount {} playout", rconn.mid), &e);
+                    None
+                }
+            }
+        }).collect()
+}
+
+/// Handles API messages until one of the mounts is done with its buffer, or they're skipped
+fn play_item(tokens: &[Arc<AtomicBool>],
+             updates: &Receiver<ApiMessage>,
+             queue: &Mutex<Queue>,
+             cfg: &Config,
+             btx: &poll::Sender<Buffer>,
+             errors: &ErrLog) {
+    // Song activity loop - ensures that the song is properly transcoding and handles any sort
+    // of API message that gets received in the meanwhile
+    let mut last_expiry = time::Instant::now();
+    loop {
+        // If any prebuffer completes, just move on to next song. We want to minimize downtime
+        // even if it means some songs get cut off early
+        if tokens.iter().any(|tok| tok.load(Ordering::Acquire)) {
+            break;
+        } else {
+            if let Ok(msg) = updates.try_recv() {
+                // Keep all these operations local just incase
+                // anything complex might need to happen in the future.
+                debug!("Received API message {:?}", msg);
+                match msg {
+                    ApiMessage::Skip => {
+                        for token in tokens {
+                            token.store(true, Ordering::Release);
                         }
-                        ApiMessage::Remove(QueuePos::Tail) => {
-                            queue.lock().unwrap().pop();
+                        break;
+                    }
+                    ApiMessage::Clear => {
+                        queue.lock().unwrap().clear();
+                    }
+                    ApiMessage::Insert(QueuePos::Head, qe) => {
+                        queue.lock().unwrap().push_head(qe);
+                    }
+                    ApiMessage::Insert(QueuePos::Tail, qe) => {
+                        queue.lock().unwrap().push(qe);
+                    }
+                    ApiMessage::Remove(QueuePos::Head) => {
+                        queue.lock().unwrap().pop_head();
+                    }
+                    ApiMessage::Remove(QueuePos::Tail) => {
+                        queue.lock().unwrap().pop();
+                    }
+                    ApiMessage::Cue(name) => {
+                        if let Err(e) = send_cue(cfg, btx, &name) {
+                            errors.report("cue", &e);
                         }
                     }
-                } else {
-                    thread::sleep(time::Duration::from_millis(20));
                 }
+            } else {
+                if last_expiry.elapsed() >= time::Duration::from_secs(EXPIRY_INTERVAL) {
+                    queue.lock().unwrap().expire();
+                    errors.flush();
+                    last_expiry = time::Instant::now();
+                }
+                thread::sleep(time::Duration::from_millis(20));
             }
         }
     }
 }
 
-fn broadcast_np(url: &str, song: QueueEntry) -> Result<(), reqwest::Error> {
+fn send_cue(cfg: &Config, btx: &poll::Sender<Buffer>, name: &str) -> Result<()> {
+    for s in cfg.streams.iter().filter(|s| s.cues) {
+        btx.send(Buffer::new(s.id, BufferData::Cue(name.to_owned())))
+            .map_err(|_| Error::Socket(io::Error::new(io::ErrorKind::BrokenPipe, "broadcaster is gone")))?;
+    }
+    Ok(())
+}
+
+fn broadcast_np(url: &str, song: QueueEntry) -> Result<()> {
     let client = reqwest::Client::new()?;
     client.post(url)?
         .json(&song.serialize())?
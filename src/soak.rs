@@ -0,0 +1,221 @@
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::io::{self, Write, BufWriter};
+use std::{env, fs, process, thread, time};
+use std::f64::consts::PI;
+
+use serde_json as serde;
+use serde_json::Map;
+use serde_json::Value as JSON;
+use rouille;
+
+use api::{ApiMessage, QueuePos};
+use config::{Config, RandomSource};
+use queue::{Queue, NewQueueEntry};
+use events::Events;
+use errlog::ErrLog;
+use library::Library;
+use util::Rng;
+use {broadcast, radio, tc_queue};
+
+const TONES: usize = 8;
+const SAMPLE_RATE: u32 = 44100;
+// Seconds between invariant checks
+const CHECK_INTERVAL: u64 = 10;
+// Number of consecutive failed checks before a leak is reported, so that transcodes which are
+// still winding down after a skip aren't counted
+const CHECK_STRIKES: usize = 3;
+// Extra threads tolerated above the baseline taken after warmup
+const THREAD_SLACK: usize = 16;
+
+/// Runs the full playout pipeline against synthetic tones for `duration`, hammering it with
+/// skips and queue mutations while checking that transcodes, buffers and threads don't leak.
+/// Exits the process with a non-zero status if an invariant is violated.
+pub fn run(mut cfg: Config, duration: time::Duration) {
+    let dir = env::temp_dir().join("kawa-soak");
+    let tones = match generate_tones(&dir) {
+        Ok(t) => t,
+        Err(e) => {
+            error!("Failed to generate synthetic tones in {:?}: {}", dir, e);
+            process::exit(1);
+        }
+    };
+
+    let addr = match start_source(tones.clone()) {
+        Ok(a) => a,
+        Err(e) => {
+            error!("Failed to start synthetic random source: {}", e);
+            process::exit(1);
+        }
+    };
+    cfg.queue.random = RandomSource::Api(format!("http://{}/random", addr));
+    cfg.queue.np = format!("http://{}/np", addr);
+    // Don't pollute the history with generated tones
+    cfg.queue.history = None;
+
+    info!("Starting soak test for {}s", duration.as_secs());
+    let events = Events::new(&cfg);
+    let errors = ErrLog::new();
+    let queue = match Queue::new(cfg.clone(), events.clone(), errors.clone(), Library::new()) {
+        Ok(q) => Arc::new(Mutex::new(q)),
+        Err(e) => {
+            error!("Failed to initialize queue: {}", e);
+            process::exit(1);
+        }
+    };
+    let listeners = Arc::new(Mutex::new(HashMap::new()));
+    let (tx, rx) = mpsc::channel();
+    let btx = match broadcast::start(&cfg, listeners.clone(), events) {
+        Ok((btx, _)) => btx,
+        Err(e) => {
+            error!("Failed to start broadcaster: {}", e);
+            process::exit(1);
+        }
+    };
+    {
+        let cfg = cfg.clone();
+        let queue = queue.clone();
+        thread::spawn(move || radio::start_streams(cfg, queue, rx, btx, listeners, errors));
+    }
+
+    let start = time::Instant::now();
+    let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+    let mut last_check = time::Instant::now();
+    let mut baseline = None;
+    let mut strikes = 0;
+    let mut actions = 0u64;
+    // Allow for both the initial transcodes and buffers per stream, plus a set being cancelled
+    let max_live = cfg.streams.len() * 3;
+
+    while start.elapsed() < duration {
+        let path = tones[(rng.next_u64() % tones.len() as u64) as usize].clone();
+        let tone = NewQueueEntry { data: Map::new(), path, ..Default::default() };
+        let msg = match rng.next_u64() % 10 {
+            0 | 1 | 2 => ApiMessage::Insert(QueuePos::Tail, tone),
+            3 => ApiMessage::Insert(QueuePos::Head, tone),
+            4 => ApiMessage::Remove(QueuePos::Head),
+            5 => ApiMessage::Remove(QueuePos::Tail),
+            6 => ApiMessage::Clear,
+            _ => ApiMessage::Skip,
+        };
+        if tx.send(msg).is_err() {
+            error!("Radio loop died after {} actions", actions);
+            process::exit(1);
+        }
+        actions += 1;
+        thread::sleep(time::Duration::from_millis(200 + rng.next_u64() % 1800));
+
+        if last_check.elapsed() < time::Duration::from_secs(CHECK_INTERVAL) {
+            continue;
+        }
+        last_check = time::Instant::now();
+
+        let (writers, readers) = tc_queue::live();
+        let threads = thread_count();
+        let base = *baseline.get_or_insert(threads);
+        info!("Soak: {}s elapsed, {} actions, {} queued, {} transcodes, {} buffers, {:?} threads",
+              start.elapsed().as_secs(), actions, queue.lock().unwrap().entries().len(),
+              writers, readers, threads);
+
+        let thread_leak = match (threads, base) {
+            (Some(t), Some(b)) => t > b + THREAD_SLACK,
+            _ => false,
+        };
+        if writers > max_live || readers > max_live || thread_leak {
+            strikes += 1;
+            warn!("Soak invariant violated ({}/{})", strikes, CHECK_STRIKES);
+            if strikes >= CHECK_STRIKES {
+                error!("Soak test failed: {} transcodes, {} buffers live (max {}), {:?} threads (baseline {:?})",
+                       writers, readers, max_live, threads, base);
+                process::exit(1);
+            }
+        } else {
+            strikes = 0;
+        }
+    }
+    info!("Soak test passed: {} actions over {}s", actions, duration.as_secs());
+    process::exit(0);
+}
+
+/// Serves the synthetic tones as the random source and swallows np broadcasts
+fn start_source(tones: Vec<String>) -> Result<String, String> {
+    let counter = AtomicUsize::new(0);
+    let server = rouille::Server::new(("127.0.0.1", 0), move |req| {
+        if req.url() == "/random" {
+            let i = counter.fetch_add(1, Ordering::Relaxed);
+            rouille::Response::from_data("application/json", serde::to_string(&json_path(&tones[i % tones.len()])).unwrap())
+        } else {
+            rouille::Response::text("")
+        }
+    }).map_err(|e| e.to_string())?;
+    let addr = server.server_addr().to_string();
+    thread::spawn(move || server.run());
+    Ok(addr)
+}
+
+fn json_path(path: &str) -> Map<String, JSON> {
+    let mut m = Map::new();
+    m.insert("path".to_owned(), JSON::String(path.to_owned()));
+    m
+}
+
+fn generate_tones(dir: &Path) -> io::Result<Vec<String>> {
+    fs::create_dir_all(dir)?;
+    let mut paths = Vec::new();
+    for i in 0..TONES {
+        let path: PathBuf = dir.join(format!("tone{}.wav", i));
+        // Short tracks of 1 to 8 seconds, so track changes happen constantly
+        write_tone(&path, 220. * (i + 1) as f64, 1 + i as u32)?;
+        paths.push(path.to_string_lossy().into_owned());
+    }
+    Ok(paths)
+}
+
+/// Writes a 16 bit stereo PCM WAV sine tone
+fn write_tone(path: &Path, freq: f64, secs: u32) -> io::Result<()> {
+    let samples = SAMPLE_RATE * secs;
+    let data_len = samples * 4;
+    let mut w = BufWriter::new(fs::File::create(path)?);
+    w.write_all(b"RIFF")?;
+    w.write_all(&le32(36 + data_len))?;
+    w.write_all(b"WAVEfmt ")?;
+    w.write_all(&le32(16))?;
+    w.write_all(&le16(1))?; // PCM
+    w.write_all(&le16(2))?; // channels
+    w.write_all(&le32(SAMPLE_RATE))?;
+    w.write_all(&le32(SAMPLE_RATE * 4))?; // byte rate
+    w.write_all(&le16(4))?; // block align
+    w.write_all(&le16(16))?; // bits per sample
+    w.write_all(b"data")?;
+    w.write_all(&le32(data_len))?;
+    for n in 0..samples {
+        let v = (2. * PI * freq * n as f64 / SAMPLE_RATE as f64).sin() * 8000.;
+        let s = le16(v as i16 as u16);
+        w.write_all(&s)?;
+        w.write_all(&s)?;
+    }
+    w.flush()
+}
+
+fn le16(v: u16) -> [u8; 2] {
+    [v as u8, (v >> 8) as u8]
+}
+
+fn le32(v: u32) -> [u8; 4] {
+    [v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]
+}
+
+#[cfg(target_os = "linux")]
+fn thread_count() -> Option<usize> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    status.lines()
+        .find(|l| l.starts_with("Threads:"))
+        .and_then(|l| l["Threads:".len()..].trim().parse().ok())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn thread_count() -> Option<usize> {
+    None
+}
@@ -0,0 +1,68 @@
+use std::io::Read;
+use std::{thread, time};
+
+use reqwest;
+use serde_json as serde;
+use serde_json::Value as JSON;
+
+use config::StandbyConfig;
+use error::{Error, Result};
+use queue::NewQueueEntry;
+
+/// Mirrors the primary's queue until its heartbeat stops, returning the last known state of it
+/// (the interrupted track first) so that this instance can take over.
+pub fn wait_for_takeover(cfg: &StandbyConfig) -> Vec<NewQueueEntry> {
+    info!("Running as standby for {}", cfg.primary);
+    let mut mirror = Vec::new();
+    let mut last_ok = time::Instant::now();
+    let timeout = time::Duration::from_secs(cfg.timeout);
+    let client = reqwest::ClientBuilder::new()
+        .and_then(|mut b| b.timeout(time::Duration::from_secs(cfg.interval.max(1))).build());
+    loop {
+        let res = match client {
+            Ok(ref c) => mirror_primary(c, &cfg.primary),
+            Err(ref e) => Err(Error::BadResponse(format!("failed to create http client: {}", e))),
+        };
+        match res {
+            Ok(entries) => {
+                if last_ok.elapsed() > time::Duration::from_secs(cfg.interval * 2) {
+                    info!("Primary is reachable, mirroring {} entries", entries.len());
+                }
+                mirror = entries;
+                last_ok = time::Instant::now();
+            }
+            Err(e) => {
+                warn!("Primary heartbeat failed ({}s ago last succeeded): {}", last_ok.elapsed().as_secs(), e);
+                if last_ok.elapsed() >= timeout {
+                    warn!("Primary is unresponsive, taking over with {} mirrored entries", mirror.len());
+                    return mirror;
+                }
+            }
+        }
+        thread::sleep(time::Duration::from_secs(cfg.interval));
+    }
+}
+
+fn mirror_primary(client: &reqwest::Client, primary: &str) -> Result<Vec<NewQueueEntry>> {
+    let mut entries = Vec::new();
+    // The fallback has no path, which conveniently means it isn't mirrored
+    if let Some(np) = NewQueueEntry::deserialize(get(client, &format!("{}/np", primary))?) {
+        entries.push(np);
+    }
+    match get(client, &format!("{}/queue", primary))? {
+        JSON::Array(q) => entries.extend(q.into_iter().filter_map(NewQueueEntry::deserialize)),
+        _ => return Err(Error::BadResponse(format!("queue of primary is not a list"))),
+    }
+    Ok(entries)
+}
+
+fn get(client: &reqwest::Client, url: &str) -> Result<JSON> {
+    let mut resp = client.get(url)?.send()?;
+    if !resp.status().is_success() {
+        return Err(Error::BadResponse(format!("{} returned {}", url, resp.status())));
+    }
+    let mut body = String::new();
+    resp.read_to_string(&mut body)
+        .map_err(|e| Error::BadResponse(format!("failed to read {}: {}", url, e)))?;
+    serde::from_str(&body).map_err(|e| Error::BadResponse(format!("{} is not valid json: {}", url, e)))
+}
@@ -1,8 +1,9 @@
-use std::sync::{atomic, mpsc, Arc};
+use std::sync::{atomic, mpsc, Arc, Mutex};
 use std::{mem, io, time};
 
 use kaeru::Sink;
 use broadcast::BufferData;
+use faults;
 
 pub struct QW {
     queue: mpsc::SyncSender<BufferData>,
@@ -10,13 +11,27 @@ pub struct QW {
     writing_header: bool,
     writing_trailer: bool,
     done: Arc<atomic::AtomicBool>,
+    stats: Arc<OutputStats>,
 }
 
 pub struct QR {
     pub done: Arc<atomic::AtomicBool>,
+    pub stats: Arc<OutputStats>,
     queue: mpsc::Receiver<BufferData>,
 }
 
+/// Counters of what went through a queue
+#[derive(Debug, Default)]
+pub struct OutputStats {
+    pub bytes: atomic::AtomicUsize,
+    /// Microseconds the writer spent waiting for the reader to take buffers
+    pub blocked: atomic::AtomicUsize,
+}
+
+// Live queue halves, used to detect leaked transcodes/buffers
+static WRITERS: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+static READERS: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+
 pub enum BufferRes {
     Data(BufferData),
     Timeout,
@@ -26,26 +41,106 @@ pub enum BufferRes {
 pub fn new() -> (QW, QR) {
     let (tx, rx) = mpsc::sync_channel(15);
     let done = Arc::new(atomic::AtomicBool::new(false));
+    let stats = Arc::new(OutputStats::default());
+    WRITERS.fetch_add(1, atomic::Ordering::Relaxed);
+    READERS.fetch_add(1, atomic::Ordering::Relaxed);
     (
-        QW::new(tx, done.clone()),
-        QR { queue: rx, done }
+        QW::new(tx, done.clone(), stats.clone()),
+        QR { queue: rx, done, stats }
     )
 }
 
+/// Creates a reader which replays already encoded buffers
+pub fn from_cache(bufs: &[BufferData]) -> QR {
+    let (tx, rx) = mpsc::sync_channel(bufs.len());
+    for b in bufs {
+        tx.send(b.clone()).unwrap();
+    }
+    READERS.fetch_add(1, atomic::Ordering::Relaxed);
+    QR { queue: rx, done: Arc::new(atomic::AtomicBool::new(false)), stats: Arc::new(OutputStats::default()) }
+}
+
+/// Sink which collects all encoded buffers, so they can be replayed later through `from_cache`
+pub struct Collector {
+    bufs: Arc<Mutex<Vec<BufferData>>>,
+    buf: io::Cursor<Vec<u8>>,
+    writing_trailer: bool,
+}
+
+impl Collector {
+    pub fn new() -> (Collector, Arc<Mutex<Vec<BufferData>>>) {
+        let bufs = Arc::new(Mutex::new(Vec::new()));
+        (Collector { bufs: bufs.clone(), buf: io::Cursor::new(Vec::new()), writing_trailer: false }, bufs)
+    }
+
+    fn take(&mut self) -> Vec<u8> {
+        mem::replace(&mut self.buf, io::Cursor::new(Vec::new())).into_inner()
+    }
+}
+
+impl io::Write for Collector {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.buf.flush()
+    }
+}
+
+impl Sink for Collector {
+    fn header_written(&mut self) {
+        let b = self.take();
+        self.bufs.lock().unwrap().push(BufferData::Header(b));
+    }
+
+    fn packet_written(&mut self, pts: f64) {
+        if self.writing_trailer {
+            return;
+        }
+        let b = self.take();
+        self.bufs.lock().unwrap().push(BufferData::Frame { data: b, pts });
+    }
+
+    fn body_written(&mut self) {
+        self.writing_trailer = true;
+    }
+}
+
+impl Drop for Collector {
+    fn drop(&mut self) {
+        let b = self.take();
+        self.bufs.lock().unwrap().push(BufferData::Trailer(b));
+    }
+}
+
 impl QW {
-    fn new(q: mpsc::SyncSender<BufferData>, done: Arc<atomic::AtomicBool>) -> QW {
+    fn new(q: mpsc::SyncSender<BufferData>, done: Arc<atomic::AtomicBool>, stats: Arc<OutputStats>) -> QW {
         QW {
             queue: q,
             buf: io::Cursor::new(Vec::with_capacity(1024)),
             writing_header: true,
             writing_trailer: false,
             done,
+            stats,
         }
     }
 
     fn done(&self) -> bool {
         self.done.load(atomic::Ordering::Acquire)
     }
+
+    /// Hands a buffer to the reader, returning false if it's gone
+    fn send(&self, bd: BufferData) -> bool {
+        faults::stall_transcode();
+        self.stats.bytes.fetch_add(bd.frame().len(), atomic::Ordering::Relaxed);
+        let start = time::Instant::now();
+        let res = self.queue.send(bd);
+        let blocked = start.elapsed();
+        self.stats.blocked.fetch_add(blocked.as_secs() as usize * 1_000_000 + blocked.subsec_nanos() as usize / 1000,
+                                     atomic::Ordering::Relaxed);
+        res.is_ok()
+    }
 }
 
 impl io::Write for QW {
@@ -69,7 +164,7 @@ impl Sink for QW {
         self.writing_header = false;
         let nb = io::Cursor::new(Vec::with_capacity(1024));
         let ob = mem::replace(&mut self.buf, nb);
-        if self.queue.send(BufferData::Header(ob.into_inner())).is_err() {
+        if !self.send(BufferData::Header(ob.into_inner())) {
             self.done.store(true, atomic::Ordering::Release);
         }
     }
@@ -85,7 +180,7 @@ impl Sink for QW {
             data: ob.into_inner(),
             pts,
         };
-        if self.queue.send(bd).is_err() {
+        if !self.send(bd) {
             self.done.store(true, atomic::Ordering::Release);
         }
     }
@@ -100,9 +195,10 @@ impl Drop for QW {
         if self.writing_trailer {
             let nb = io::Cursor::new(Vec::with_capacity(0));
             let ob = mem::replace(&mut self.buf, nb);
-            if self.queue.send(BufferData::Trailer(ob.into_inner())).is_err() { }
+            self.send(BufferData::Trailer(ob.into_inner()));
         }
         self.done.store(true, atomic::Ordering::Release);
+        WRITERS.fetch_sub(1, atomic::Ordering::Relaxed);
     }
 }
 
@@ -119,5 +215,11 @@ impl QR {
 impl Drop for QR {
     fn drop(&mut self) {
         self.done.store(true, atomic::Ordering::Release);
+        READERS.fetch_sub(1, atomic::Ordering::Relaxed);
     }
 }
+
+/// Returns the number of live (writer, reader) queue halves
+pub fn live() -> (usize, usize) {
+    (WRITERS.load(atomic::Ordering::Relaxed), READERS.load(atomic::Ordering::Relaxed))
+}
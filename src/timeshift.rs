@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::{cmp, thread, time};
+
+use broadcast::{self, Buffer, BufferData, Chunker};
+use config::{Config, StreamConfig, TimeshiftConfig};
+use util::now;
+
+// Length of a segment file in seconds
+const SEGMENT_SECS: u64 = 10;
+// Minimum delay of a listener, so that only complete segments are ever sent
+const MIN_DELAY: u64 = 2 * SEGMENT_SECS;
+// Segment files start with the length of the header copied into them, as a big endian u64
+const PREFIX_LEN: usize = 8;
+
+// Timeshifted listeners being served
+static LISTENERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Records the encoded output of all mounts with timeshift enabled into rolling segment files.
+struct Recorder {
+    dir: PathBuf,
+    mounts: HashMap<usize, Recording>,
+}
+
+struct Recording {
+    dir: PathBuf,
+    window: u64,
+    header: Vec<u8>,
+    segment: Option<(u64, File)>,
+}
+
+/// Starts the recorder if any mount has timeshift enabled, returning the channel to feed it
+pub fn start(cfg: &Config) -> Option<mpsc::Sender<Buffer>> {
+    let base = match cfg.timeshift {
+        Some(ref t) => PathBuf::from(&t.dir),
+        None => return None,
+    };
+    let mounts: HashMap<_, _> = cfg.streams.iter().enumerate()
+        .filter_map(|(mid, s)| s.timeshift.map(|window| (mid, Recording {
+            dir: mount_dir(&base, s),
+            window: window * 60,
+            header: Vec::new(),
+            segment: None,
+        })))
+        .collect();
+    if mounts.is_empty() {
+        return None;
+    }
+
+    let (tx, rx) = mpsc::channel();
+    let mut r = Recorder { dir: base, mounts };
+    thread::spawn(move || r.run(rx));
+    Some(tx)
+}
+
+/// Serves a listener the mount as it was `minutes` ago, then keeps streaming at that delay. Every
+/// listener gets a thread of its own, so there are at most `max_listeners` of them; further ones
+/// are turned away.
+pub fn serve(mut conn: TcpStream, name: String, stream: StreamConfig, cfg: &TimeshiftConfig, minutes: u64) {
+    // A listener which stops reading must not hold its thread forever
+    let timeout = Some(time::Duration::from_secs(broadcast::CLIENT_TIMEOUT));
+    if conn.set_nonblocking(false).is_err() || conn.set_write_timeout(timeout).is_err() {
+        return;
+    }
+    let slot = match Slot::take(cfg.max_listeners) {
+        Some(s) => s,
+        None => {
+            debug!("Turning away timeshift listener, {} are served already", cfg.max_listeners);
+            let _ = conn.write_all(b"HTTP/1.1 503 Service Unavailable\r\nConnection: close\r\n\r\n");
+            return;
+        }
+    };
+    let dir = mount_dir(Path::new(&cfg.dir), &stream);
+    thread::spawn(move || {
+        let _slot = slot;
+        if conn.write_all(broadcast::response_header(&name, &stream, false).as_bytes()).is_err() {
+            return;
+        }
+        let target = now().saturating_sub(minutes * 60);
+        let mut delay = minutes * 60;
+        let mut chunker = Chunker::new();
+        let mut last = None;
+        // Whether the listener got a header yet
+        let mut started = false;
+        loop {
+            let segs = segments(&dir);
+            let seg = match last {
+                // Start with the segment covering the requested point in time, or the oldest one
+                // if we don't have that much history
+                None => segs.iter().rev().find(|s| **s <= target).or(segs.first()).cloned(),
+                Some(l) => segs.into_iter().find(|s| *s > l),
+            };
+            let seg = match seg {
+                Some(s) if last.is_some() || now() >= s + MIN_DELAY => s,
+                _ => {
+                    thread::sleep(time::Duration::from_secs(1));
+                    continue;
+                }
+            };
+            if last.is_none() {
+                delay = cmp::min(delay, now() - seg);
+            }
+            // Only send a segment once it's complete and due, so we stay at the requested delay
+            while now() < seg + delay {
+                thread::sleep(time::Duration::from_millis(500));
+            }
+            match read_segment(&dir, seg, !started) {
+                Ok(data) => {
+                    if chunker.write_all(&mut conn, &data).is_err() {
+                        debug!("Timeshift listener disconnected");
+                        return;
+                    }
+                    started = true;
+                }
+                Err(e) => debug!("Timeshift segment {} disappeared: {}", seg, e),
+            }
+            last = Some(seg);
+        }
+    });
+}
+
+/// A listener's share of the limit on timeshifted listeners, given back when dropped
+struct Slot;
+
+impl Slot {
+    fn take(max: usize) -> Option<Slot> {
+        if LISTENERS.fetch_add(1, Ordering::SeqCst) < max {
+            Some(Slot)
+        } else {
+            LISTENERS.fetch_sub(1, Ordering::SeqCst);
+            None
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        LISTENERS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Recorder {
+    fn run(&mut self, rx: mpsc::Receiver<Buffer>) {
+        for buf in rx {
+            let mid = buf.mount;
+            if let Some(rec) = self.mounts.get_mut(&mid) {
+                if let Err(e) = rec.record(buf.data, now()) {
+                    warn!("Failed to record timeshift segment in {:?}: {}", rec.dir, e);
+                    rec.segment = None;
+                }
+            }
+        }
+        debug!("Timeshift recorder for {:?} stopped", self.dir);
+    }
+}
+
+impl Recording {
+    fn record(&mut self, data: BufferData, now: u64) -> io::Result<()> {
+        if let BufferData::Header(ref h) = data {
+            self.header = h.clone();
+        }
+        let rotate = match self.segment {
+            Some((start, _)) => now >= start + SEGMENT_SECS,
+            None => true,
+        };
+        if rotate {
+            fs::create_dir_all(&self.dir)?;
+            let mut f = File::create(segment_path(&self.dir, now))?;
+            // Every segment starts with the current header, so that listeners can start from it.
+            // Its length is recorded so that it can be skipped for listeners who already got it.
+            let header: &[u8] = if data.is_header() { &[] } else { &self.header };
+            let mut len = [0; PREFIX_LEN];
+            for (i, b) in len.iter_mut().enumerate() {
+                *b = (header.len() as u64 >> (8 * (PREFIX_LEN - 1 - i))) as u8;
+            }
+            f.write_all(&len)?;
+            f.write_all(header)?;
+            self.segment = Some((now, f));
+            self.prune(now);
+        }
+        if let Some((_, ref mut f)) = self.segment {
+            f.write_all(data.frame())?;
+        }
+        Ok(())
+    }
+
+    fn prune(&self, now: u64) {
+        let cutoff = now.saturating_sub(self.window + SEGMENT_SECS);
+        for seg in segments(&self.dir).into_iter().filter(|s| *s < cutoff) {
+            if let Err(e) = fs::remove_file(segment_path(&self.dir, seg)) {
+                warn!("Failed to remove old timeshift segment: {}", e);
+            }
+        }
+    }
+}
+
+/// Returns the start times of all segments in the directory, sorted
+fn segments(dir: &Path) -> Vec<u64> {
+    let mut segs: Vec<u64> = fs::read_dir(dir)
+        .map(|entries| {
+            entries.filter_map(|e| e.ok())
+                .filter_map(|e| {
+                    let name = e.file_name().to_string_lossy().into_owned();
+                    if name.ends_with(".seg") {
+                        name[..name.len() - 4].parse().ok()
+                    } else {
+                        None
+                    }
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    segs.sort();
+    segs
+}
+
+fn mount_dir(base: &Path, stream: &StreamConfig) -> PathBuf {
+    base.join(stream.mount.replace('/', "_"))
+}
+
+fn segment_path(dir: &Path, start: u64) -> PathBuf {
+    dir.join(format!("{}.seg", start))
+}
+
+/// Reads a segment to send to a listener. The header copied into the segment is only included in
+/// the first one a listener gets, later ones would restart the stream.
+fn read_segment(dir: &Path, start: u64, first: bool) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    File::open(segment_path(dir, start))?.read_to_end(&mut data)?;
+    if data.len() < PREFIX_LEN {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "segment is truncated"));
+    }
+    let len = data[..PREFIX_LEN].iter().fold(0, |l, b| l << 8 | *b as u64) as usize;
+    let skip = if first { 0 } else { len };
+    Ok(data.split_off(cmp::min(PREFIX_LEN + skip, data.len())))
+}
+
+#[test]
+fn test_slots() {
+    let held: Vec<_> = (0..3).map(|_| Slot::take(3)).collect();
+    assert!(held.iter().all(|s| s.is_some()));
+    assert!(Slot::take(3).is_none());
+    drop(held);
+    assert!(Slot::take(3).is_some());
+}
+
+#[test]
+fn test_header_once() {
+    let dir = ::std::env::temp_dir().join("kawa-test-timeshift");
+    let _ = fs::remove_dir_all(&dir);
+    let mut rec = Recording { dir: dir.clone(), window: 60, header: Vec::new(), segment: None };
+    let frame = |d: &[u8]| BufferData::Frame { data: d.to_vec(), pts: 0. };
+    rec.record(BufferData::Header(b"HDR".to_vec()), 1000).unwrap();
+    rec.record(frame(b"one"), 1000).unwrap();
+    rec.record(frame(b"two"), 1000 + SEGMENT_SECS).unwrap();
+    rec.segment = None;
+
+    let mut served = read_segment(&dir, 1000, true).unwrap();
+    served.extend(read_segment(&dir, 1000 + SEGMENT_SECS, false).unwrap());
+    assert_eq!(served, b"HDRonetwo".to_vec());
+    // Listeners starting later still get the header first
+    assert_eq!(read_segment(&dir, 1000 + SEGMENT_SECS, true).unwrap(), b"HDRtwo".to_vec());
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,229 @@
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::HashMap;
+use std::{thread, time};
+
+use kaeru;
+
+use events::{Event, Events, OutputReport};
+use queue::QueueEntry;
+use tc_queue::OutputStats;
+
+// Time a cancelled transcoder gets to exit before its thread is detached
+const CANCEL_TIMEOUT: u64 = 5;
+
+/// Keeps track of all live transcoder threads and tears down cancelled ones.
+#[derive(Clone)]
+pub struct Registry {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    live: HashMap<u64, TranscodeInfo>,
+    reaper: mpsc::Sender<Transcoder>,
+    last_id: u64,
+    events: Events,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TranscodeInfo {
+    pub id: u64,
+    pub thread: String,
+    pub entry: u64,
+    pub path: String,
+    /// Seconds since the transcode started
+    pub age: u64,
+    pub cancelled: bool,
+    #[serde(skip)]
+    pub graph: kaeru::GraphInfo,
+    #[serde(skip)]
+    started: time::Instant,
+}
+
+/// Handle to a running transcode. Dropping it cancels the transcode, after which its thread is
+/// joined in the background, or detached if it fails to exit in time.
+pub struct Transcoder {
+    id: u64,
+    tokens: Vec<Arc<AtomicBool>>,
+    finished: mpsc::Receiver<()>,
+    handle: Option<thread::JoinHandle<()>>,
+    registry: Option<Registry>,
+}
+
+impl Registry {
+    pub fn new(events: Events) -> Registry {
+        let (tx, rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("transcode-reaper".to_owned())
+            .spawn(move || reap(rx))
+            .expect("failed to spawn transcode reaper");
+        Registry {
+            inner: Arc::new(Mutex::new(Inner {
+                live: HashMap::new(),
+                reaper: tx,
+                last_id: 0,
+                events,
+            })),
+        }
+    }
+
+    /// Runs the graph on a new thread. `tokens` are the done flags of the graph's outputs,
+    /// which are set to make the transcode bail out when it gets cancelled. `outputs` are the
+    /// mounts of the outputs along with their stats, which go into the report of the track.
+    pub fn spawn(&self,
+                 entry: &QueueEntry,
+                 g: kaeru::Graph,
+                 tokens: Vec<Arc<AtomicBool>>,
+                 outputs: Vec<(String, Arc<OutputStats>)>)
+                 -> Transcoder {
+        let id = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.last_id += 1;
+            inner.last_id
+        };
+        let name = format!("transcode-{}", id);
+        let graph = g.info();
+        let sample_rate = graph.input.sample_rate;
+        self.inner.lock().unwrap().live.insert(id, TranscodeInfo {
+            id,
+            thread: name.clone(),
+            entry: entry.id,
+            path: entry.path.clone(),
+            age: 0,
+            cancelled: false,
+            graph,
+            started: time::Instant::now(),
+        });
+        let stats = g.stats();
+        let report_entry = entry.clone();
+        let report_tokens = tokens.clone();
+
+        let (tx, rx) = mpsc::channel();
+        let registry = self.clone();
+        let handle = thread::Builder::new()
+            .name(name)
+            .spawn(move || {
+                debug!("Starting transcode");
+                let start = time::Instant::now();
+                // The graph is consumed by run, so all ffmpeg contexts are freed by the time it
+                // returns
+                let res = g.run();
+                if let Err(ref e) = res {
+                    debug!("transcode completed with err: {}", e);
+                }
+                debug!("Completed transcode");
+                let cancelled = res.is_err() && report_tokens.iter().any(|t| t.load(Ordering::Acquire));
+                let duration = stats.samples.load(Ordering::Relaxed) as f64 / sample_rate as f64;
+                let blocked: usize = outputs.iter().map(|&(_, ref s)| s.blocked.load(Ordering::Relaxed)).sum();
+                let busy = secs(start.elapsed()) - blocked as f64 / 1e6;
+                let realtime_factor = if busy > 0. { Some(duration / busy) } else { None };
+                let decode_errors = stats.decode_errors.load(Ordering::Relaxed);
+                // Known now that run freed the graph
+                let gain = *stats.gain.lock().unwrap();
+                info!("Transcode of {} ended{}: {:.1}s decoded at {:.1}x realtime, {} decode errors skipped",
+                      report_entry.path,
+                      if cancelled { " early, cancelled" } else if res.is_err() { " with an error" } else { "" },
+                      duration, realtime_factor.unwrap_or(0.), decode_errors);
+                let report = Event::TrackReport {
+                    entry: report_entry.serialize(),
+                    duration: Some(duration),
+                    realtime_factor,
+                    decode_errors,
+                    gain,
+                    outputs: outputs.iter()
+                        .map(|&(ref mount, ref s)| OutputReport { mount: mount.clone(), bytes: s.bytes.load(Ordering::Relaxed) })
+                        .collect(),
+                    fallback: false,
+                    cancelled,
+                    error: match res {
+                        Err(ref e) if !cancelled => Some(e.to_string()),
+                        _ => None,
+                    },
+                };
+                let events = {
+                    let mut inner = registry.inner.lock().unwrap();
+                    inner.live.remove(&id);
+                    inner.events.clone()
+                };
+                events.emit(report);
+                tx.send(()).ok();
+            })
+            .expect("failed to spawn transcode thread");
+
+        Transcoder {
+            id,
+            tokens,
+            finished: rx,
+            handle: Some(handle),
+            registry: Some(self.clone()),
+        }
+    }
+
+    /// Returns all transcoder threads which are still alive
+    pub fn live(&self) -> Vec<TranscodeInfo> {
+        let inner = self.inner.lock().unwrap();
+        let mut live: Vec<_> = inner.live.values()
+            .map(|t| TranscodeInfo { age: t.started.elapsed().as_secs(), ..t.clone() })
+            .collect();
+        live.sort_by_key(|t| t.id);
+        live
+    }
+}
+
+impl Transcoder {
+    fn cancel(&mut self) {
+        for tok in self.tokens.iter() {
+            tok.store(true, Ordering::Release);
+        }
+        match self.finished.recv_timeout(time::Duration::from_secs(CANCEL_TIMEOUT)) {
+            Ok(()) | Err(mpsc::RecvTimeoutError::Disconnected) => {
+                if let Some(h) = self.handle.take() {
+                    if h.join().is_err() {
+                        warn!("Transcode {} panicked", self.id);
+                    }
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                warn!("Transcode {} did not exit within {}s of being cancelled, detaching it",
+                      self.id, CANCEL_TIMEOUT);
+                self.handle.take();
+            }
+        }
+    }
+}
+
+impl Drop for Transcoder {
+    fn drop(&mut self) {
+        // Hand ourselves off to the reaper so the caller doesn't block on the join. The registry
+        // is taken so that the reaper's copy doesn't get handed off again.
+        if let Some(registry) = self.registry.take() {
+            let t = Transcoder {
+                id: self.id,
+                tokens: self.tokens.clone(),
+                finished: ::std::mem::replace(&mut self.finished, mpsc::channel().1),
+                handle: self.handle.take(),
+                registry: None,
+            };
+            let reaper = {
+                let mut inner = registry.inner.lock().unwrap();
+                if let Some(info) = inner.live.get_mut(&self.id) {
+                    info.cancelled = true;
+                }
+                inner.reaper.clone()
+            };
+            if let Err(mpsc::SendError(mut t)) = reaper.send(t) {
+                t.cancel();
+            }
+        }
+    }
+}
+
+fn secs(d: time::Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9
+}
+
+fn reap(rx: mpsc::Receiver<Transcoder>) {
+    for mut t in rx {
+        t.cancel();
+    }
+}
@@ -0,0 +1,145 @@
+use std::io::{self, Read};
+use std::process::Command;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Returns the extension of a path, which kawa uses as the container format of inputs.
+pub fn container_ext(path: &str) -> Option<&str> {
+    match path.rsplit('.').next() {
+        Some(e) if e.len() < path.len() && !e.contains(::std::path::is_separator) => Some(e),
+        _ => None,
+    }
+}
+
+/// Builds a command which runs cmd through the platform's shell
+#[cfg(unix)]
+pub fn shell(cmd: &str) -> Command {
+    let mut c = Command::new("sh");
+    c.arg("-c").arg(cmd);
+    c
+}
+
+#[cfg(windows)]
+pub fn shell(cmd: &str) -> Command {
+    let mut c = Command::new("cmd");
+    c.arg("/C").arg(cmd);
+    c
+}
+
+/// Wraps a reader, recording how far into the input it has read.
+pub struct TrackedReader<T> {
+    inner: T,
+    offset: Arc<AtomicUsize>,
+}
+
+impl<T: Read> TrackedReader<T> {
+    pub fn new(inner: T) -> TrackedReader<T> {
+        TrackedReader { inner, offset: Arc::new(AtomicUsize::new(0)) }
+    }
+
+    pub fn offset(&self) -> Arc<AtomicUsize> {
+        self.offset.clone()
+    }
+}
+
+impl<T: Read> Read for TrackedReader<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.offset.fetch_add(n, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+/// Reads from a shared in-memory buffer without copying it.
+pub struct SharedReader {
+    data: Arc<Vec<u8>>,
+    pos: usize,
+}
+
+impl SharedReader {
+    pub fn new(data: Arc<Vec<u8>>) -> SharedReader {
+        SharedReader { data, pos: 0 }
+    }
+}
+
+impl Read for SharedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = (&self.data[self.pos..]).read(buf)?;
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// xorshift64, for picking things at random where quality doesn't matter. The seed must not be
+/// zero.
+pub struct Rng(pub u64);
+
+impl Rng {
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+/// Returns the current unix timestamp
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Parses a UTC date of the form YYYY-MM-DD into the unix timestamp of its midnight
+pub fn parse_date(s: &str) -> Option<u64> {
+    let parts: Vec<_> = s.split('-').collect();
+    if parts.len() != 3 {
+        return None;
+    }
+    let y: i64 = parts[0].parse().ok()?;
+    let m: u32 = parts[1].parse().ok()?;
+    let d: u32 = parts[2].parse().ok()?;
+    if y < 1970 || m < 1 || m > 12 || d < 1 || d > 31 {
+        return None;
+    }
+    Some(days_from_civil(y, m, d) as u64 * 86400)
+}
+
+/// Formats a unix timestamp as a UTC "YYYY-MM-DD HH:MM:SS" string
+pub fn format_time(secs: u64) -> String {
+    let (y, m, d) = civil_from_days((secs / 86400) as i64);
+    let rem = secs % 86400;
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", y, m, d, rem / 3600, rem / 60 % 60, rem % 60)
+}
+
+// Conversions between the proleptic Gregorian calendar and days since the unix epoch, see
+// http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = (if y >= 0 { y } else { y - 399 }) / 400;
+    let yoe = y - era * 400;
+    let mp = (m as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(z: i64) -> (i64, u32, u32) {
+    let z = z + 719468;
+    let era = (if z >= 0 { z } else { z - 146096 }) / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    (if m <= 2 { yoe + era * 400 + 1 } else { yoe + era * 400 }, m, d)
+}
+
+#[test]
+fn test_dates() {
+    assert_eq!(parse_date("1970-01-01"), Some(0));
+    assert_eq!(parse_date("2000-03-01"), Some(951868800));
+    assert_eq!(format_time(951868800 + 3723), "2000-03-01 01:02:03");
+    assert_eq!(format_time(parse_date("2024-02-29").unwrap()), "2024-02-29 00:00:00");
+    assert_eq!(parse_date("2024-13-01"), None);
+}